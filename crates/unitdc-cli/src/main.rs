@@ -1,8 +1,8 @@
-use unitdc::interpreter::{Interpreter, Output};
+use unitdc::interpreter::{include::FsResolver, output::FnSink, Interpreter, Output};
 
 fn main() {
     env_logger::init();
-    let mut interpreter = Interpreter::new(Box::new(|output| match output {
+    let mut interpreter = Interpreter::new(FnSink(|output| match output {
         Output::Message(e) => eprintln!("message: {}", e),
         Output::Quantity(q) => println!("[0]: {}", q),
         Output::QuantityList(mut q) => {
@@ -11,17 +11,45 @@ fn main() {
                 println!("[{}]: {}", i, q)
             }
         }
+        Output::Warning(w) => eprintln!("{}", w),
+        Output::UnitTable(t) => eprint!("{}", t),
+        _ => {}
     }));
+    interpreter.set_include_resolver(Box::new(FsResolver));
 
     interpreter
         .run_str(include_str!("../../../unitdc.rc"))
         .expect("unitdc.rc should run");
+    // Any files given as arguments are loaded before reading input, like `@include`.
+    for path in std::env::args().skip(1) {
+        if let Err(e) = interpreter.include(&path) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+    interpreter.clear_history();
+    interpreter.set_atomic(true);
 
-    // REPL loop
+    // REPL loop, where `:undo` and `:redo` step through the history
     for line in std::io::stdin().lines() {
         let line = line.expect("line should exist");
+        match line.trim() {
+            ":undo" => {
+                if !interpreter.undo() {
+                    eprintln!("nothing to undo");
+                }
+                continue;
+            }
+            ":redo" => {
+                if !interpreter.redo() {
+                    eprintln!("nothing to redo");
+                }
+                continue;
+            }
+            _ => {}
+        }
         if let Err(e) = interpreter.run_str(&line) {
-            eprintln!("{}", e)
+            eprintln!("{}", e.render_diagnostic(&line))
         }
     }
 }
@@ -1,11 +1,50 @@
 mod utils;
 
-use js_sys::Function;
-use unitdc::interpreter::{Interpreter, Output};
+use std::collections::HashMap;
+
+use js_sys::{Function, Object, Reflect};
+use unitdc::interpreter::{
+    include::MapResolver, output::OutputSink, Interpreter, InterpreterError, Output,
+};
 use utils::set_panic_hook;
-use wasm_bindgen::prelude::*;
+use wasm_bindgen::{prelude::*, JsCast};
+
+/// Sends outputs to a JavaScript callback, as the output type and its value.
+struct JsSink(Option<Function>);
 
-static mut INTERPRETER: Option<Interpreter> = None;
+impl OutputSink for JsSink {
+    fn emit(&mut self, output: Output) {
+        let Some(callback) = &self.0 else {
+            return;
+        };
+        // Emitting cannot fail, so a callback that throws is logged rather than trapping the instance.
+        let result = serde_wasm_bindgen::to_value(&output)
+            .map_err(JsValue::from)
+            .and_then(|output| {
+                let kind = Reflect::get(&output, &JsValue::from("type"))?;
+                let value = Reflect::get(&output, &JsValue::from("value"))?;
+                callback.call2(&JsValue::NULL, &kind, &value)
+            });
+        if let Err(e) = result {
+            log(format!("could not send output: {:?}", e));
+        }
+    }
+}
+
+/// Converts an error to a JavaScript object with its `kind`, `span` and payload fields,
+/// plus a `diagnostic` string rendering it against `input`.
+fn error_to_js(error: &InterpreterError, input: &str) -> JsValue {
+    let value = match serde_wasm_bindgen::to_value(error) {
+        Ok(value) => value,
+        Err(e) => return e.into(),
+    };
+    let _ = Reflect::set(
+        &value,
+        &JsValue::from("diagnostic"),
+        &JsValue::from(error.render_diagnostic(input)),
+    );
+    value
+}
 
 #[wasm_bindgen]
 extern "C" {
@@ -13,52 +52,79 @@ extern "C" {
     fn log(s: String);
 }
 
-/// Runs a string in the interpreter.
+/// A calculator with its own interpreter, so a page can host several independent ones.
 #[wasm_bindgen]
-pub fn unitdc_input(input: String) -> Result<(), JsValue> {
-    unsafe {
-        if let Some(ref mut interpreter) = INTERPRETER {
-            interpreter.run_str(&input).map_err(|e| e.to_string())?;
-        }
-    }
-    Ok(())
+pub struct UnitDc {
+    interpreter: Interpreter<JsSink>,
 }
 
-/// Initializes the interpreter, taking a callback function to send output to.
 #[wasm_bindgen]
-pub fn unitdc_init(js_output: Function) {
-    unsafe {
-        let mut interp = Interpreter::new(Box::new(move |output| match output {
-            Output::Quantity(q) => {
-                js_output
-                    .call2(
-                        &JsValue::NULL,
-                        &JsValue::from("quantity"),
-                        &serde_wasm_bindgen::to_value(&q).unwrap(),
-                    )
-                    .unwrap();
-            }
-            Output::QuantityList(q) => {
-                js_output
-                    .call2(
-                        &JsValue::NULL,
-                        &JsValue::from("quantity_list"),
-                        &serde_wasm_bindgen::to_value(&q).unwrap(),
-                    )
-                    .unwrap();
-            }
-            Output::Message(e) => {
-                js_output
-                    .call2(&JsValue::NULL, &JsValue::from("message"), &JsValue::from(e))
-                    .unwrap();
-            }
-        }));
+impl UnitDc {
+    /// Creates a calculator. All options are optional:
+    ///
+    /// - `output`: a function called with the type and value of each output
+    /// - `rc`: initialization script to run instead of the built-in `unitdc.rc`
+    /// - `skipRc`: start without running any initialization script
+    /// - `includes`: an object mapping file names to their contents, to be served to `@include`
+    #[wasm_bindgen(constructor)]
+    pub fn new(options: Option<Object>) -> Result<UnitDc, JsValue> {
+        let options = options.unwrap_or_default();
+        let get = |key: &str| Reflect::get(&options, &JsValue::from(key));
+
+        let output = get("output")?.dyn_into::<Function>().ok();
+        let rc = get("rc")?.as_string();
+        let skip_rc = get("skipRc")?.is_truthy();
+        let includes = get("includes")?;
+        let includes: HashMap<String, String> = if includes.is_undefined() {
+            HashMap::new()
+        } else {
+            serde_wasm_bindgen::from_value(includes)?
+        };
 
-        interp
-            .run_str(include_str!("../../../unitdc.rc"))
-            .expect("could not run initialization script");
+        let mut interpreter = Interpreter::new(JsSink(output));
+        interpreter.set_include_resolver(Box::new(includes.into_iter().collect::<MapResolver>()));
+        if !skip_rc {
+            let rc = rc.as_deref().unwrap_or(include_str!("../../../unitdc.rc"));
+            interpreter.run_str(rc).map_err(|e| error_to_js(&e, rc))?;
+        }
+        interpreter.clear_history();
+        interpreter.set_atomic(true);
 
-        INTERPRETER = Some(interp);
+        Ok(UnitDc { interpreter })
+    }
+    /// Runs a string of input. Errors are objects with a `kind` code, a `message`, the `span` and
+    /// `token` that failed, fields specific to the kind, and a rendered `diagnostic`.
+    pub fn input(&mut self, input: String) -> Result<(), JsValue> {
+        self.interpreter
+            .run_str(&input)
+            .map_err(|e| error_to_js(&e, &input))?;
+        Ok(())
+    }
+    /// Reverts the last input that changed the state. Returns `false` if there is nothing to undo.
+    pub fn undo(&mut self) -> bool {
+        self.interpreter.undo()
+    }
+    /// Re-applies the last undone input. Returns `false` if there is nothing to redo.
+    pub fn redo(&mut self) -> bool {
+        self.interpreter.redo()
+    }
+    /// The values on the stack, with the top last.
+    pub fn stack(&self) -> Result<JsValue, JsValue> {
+        Ok(serde_wasm_bindgen::to_value(self.interpreter.stack())?)
+    }
+    /// Exports the interpreter state as JSON, to be restored with `importSession`.
+    #[wasm_bindgen(js_name = exportSession)]
+    pub fn export_session(&self) -> String {
+        self.interpreter.export_session()
+    }
+    /// Restores the interpreter state from JSON exported by `exportSession`, forgetting undo history.
+    #[wasm_bindgen(js_name = importSession)]
+    pub fn import_session(&mut self, json: String) -> Result<(), JsValue> {
+        self.interpreter
+            .import_session(&json)
+            .map_err(|e| error_to_js(&e, &json))?;
+        self.interpreter.clear_history();
+        Ok(())
     }
 }
 
@@ -17,27 +17,46 @@
  */
 
 
-import { useState, useReducer } from 'react'
+import { useState, useReducer, useRef } from 'react'
 
 import './App.css'
 import { Keyboard, TokenType } from './components/Keyboard'
 import { InputCell } from './components/InputCell';
-import unitdc_wasm, { unitdc_input, unitdc_init } from '../../pkg';
+import unitdc_wasm, { UnitDc } from '../../pkg';
 import { ErrorCell } from './components/ErrorCell';
-import { Quantity } from './types';
+import { InterpreterError, Quantity, UnitTable, Warning } from './types';
 import { OutputCell } from './components/OutputCell';
+import { WarningCell } from './components/WarningCell';
+import { UnitTableCell } from './components/UnitTableCell';
 import { useForceUpdate } from './util';
 
-type IoCellDef = IOTextCellDef | IOQuantityCellDef;
+type IoCellDef = IOTextCellDef | IOErrorCellDef | IOQuantityCellDef | IOWarningCellDef | IOUnitTableCellDef;
 
 interface IOQuantityCellDef {
   type: 'output',
   quantity: Quantity[]
 }
 
+interface IOWarningCellDef {
+  type: 'warning',
+  warning: Warning
+}
+
+interface IOUnitTableCellDef {
+  type: 'unit_table',
+  table: UnitTable
+}
+
 interface IOTextCellDef {
-  type: 'input' | 'message' | 'error',
+  type: 'input' | 'message',
+  text: string,
+}
+
+interface IOErrorCellDef {
+  type: 'error',
   text: string,
+  error?: InterpreterError,
+  input?: string,
 }
 
 type IoCellAction = IoCellAddAction | IoCellUpdateTextAction;
@@ -69,9 +88,12 @@ function IoCellReducer(state: IoCellDef[], action: IoCellAction) {
   }
 }
 
+const SESSION_KEY = 'unitdc-session';
+
 function App() {
   const [ioCells, ioCellsDispatch] = useReducer(IoCellReducer, []);
   const [mounted, setMounted] = useState(false);
+  const unitdc = useRef<UnitDc | null>(null);
   const [lastTokenType, setLastTokenType] = useState<TokenType | "">("");
   const forceUpdate = useForceUpdate();
 
@@ -97,7 +119,7 @@ function App() {
   }
 
 
-  const processOutput = (type: 'quantity' | 'quantity_list' | 'message', data: any) => {
+  const processOutput = (type: 'quantity' | 'quantity_list' | 'message' | 'warning' | 'unit_table', data: any) => {
     if (type === 'quantity') {
       addCells([
         {
@@ -119,6 +141,39 @@ function App() {
           text: data,
         }
       ])
+    } else if (type === 'warning') {
+      addCells([
+        {
+          type: 'warning',
+          warning: data,
+        }
+      ])
+    } else if (type === 'unit_table') {
+      addCells([
+        {
+          type: 'unit_table',
+          table: data,
+        }
+      ])
+    }
+  }
+
+  const saveSession = () => {
+    if (unitdc.current) {
+      localStorage.setItem(SESSION_KEY, unitdc.current.exportSession());
+    }
+  }
+
+  const restoreSession = () => {
+    const session = localStorage.getItem(SESSION_KEY);
+    if (session === null) {
+      return;
+    }
+    try {
+      unitdc.current?.importSession(session);
+    } catch (e) {
+      console.warn('could not restore session', e);
+      localStorage.removeItem(SESSION_KEY);
     }
   }
 
@@ -127,13 +182,16 @@ function App() {
     let text = lastInputCell().text;
     console.log('submit', text);
     try {
-      unitdc_input(text)
+      unitdc.current?.input(text)
       success = true;
+      saveSession();
     } catch (e) {
       addCells([
         {
           type: 'error',
-          text: (e as any).toString(),
+          text: (e as any).message ?? (e as any).toString(),
+          error: (e as any).kind ? e as InterpreterError : undefined,
+          input: text,
         }
       ])
     }
@@ -145,10 +203,31 @@ function App() {
     ])
   };
 
+  const moveHistory = (action: 'undo' | 'redo') => {
+    const moved = action === 'undo' ? unitdc.current?.undo() : unitdc.current?.redo();
+    if (moved) {
+      saveSession();
+    }
+    addCells([
+      moved ? {
+        type: 'message',
+        text: `${action} done`,
+      } : {
+        type: 'error',
+        text: `nothing to ${action}`,
+      },
+      {
+        type: 'input',
+        text: lastInputCell().text,
+      }
+    ])
+  };
+
   if (!mounted) {
     setMounted(true);
     unitdc_wasm().then(() => {
-      unitdc_init(processOutput);
+      unitdc.current = new UnitDc({ output: processOutput });
+      restoreSession();
       addCells([
         {
           type: 'input',
@@ -200,12 +279,20 @@ function App() {
                   )
                 case 'error':
                   return (
-                    <ErrorCell key={index} text={cell.text} />
+                    <ErrorCell key={index} text={cell.text} error={cell.error} input={cell.input} />
                   )
                 case 'message':
                   return (
                     <pre key={index}>{cell.text}</pre>
                   )
+                case 'warning':
+                  return (
+                    <WarningCell key={index} warning={cell.warning} />
+                  )
+                case 'unit_table':
+                  return (
+                    <UnitTableCell key={index} table={cell.table} />
+                  )
               }
             })
           }
@@ -239,6 +326,10 @@ function App() {
               case 'submit':
                 submit();
                 break;
+              case 'undo':
+              case 'redo':
+                moveHistory(action);
+                break;
             }
           }}
         />
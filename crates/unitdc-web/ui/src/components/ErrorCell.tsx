@@ -16,15 +16,42 @@
  * SPDX-License-Identifier: Apache-2.0
  */
 
+import { InterpreterError } from '../types';
+
 export interface ErrorCellProps {
     text: string
+    error?: InterpreterError
+    input?: string
+}
+
+// Splits the line of input the error occurred at around the failing token.
+function highlight(error: InterpreterError, input: string) {
+    if (!error.span || error.file) {
+        return null;
+    }
+    const { start, end } = error.span;
+    const line = Array.from(input.split('\n')[start.line - 1] ?? '');
+    const from = Math.min(start.column - 1, line.length);
+    const to = end.line === start.line ? Math.max(from + 1, end.column - 1) : line.length;
+    return {
+        before: line.slice(0, from).join(''),
+        token: line.slice(from, to).join(''),
+        after: line.slice(to).join(''),
+    };
 }
 
 export function ErrorCell(props: ErrorCellProps) {
+    const parts = props.error && props.input !== undefined ? highlight(props.error, props.input) : null;
     return (
         <div className="unitdc-io error">
             <label className="prompt">Error:</label>
             <div className="error-text">{props.text}</div>
+            {
+                parts &&
+                <div className="error-text" data-kind={props.error?.kind}>
+                    {parts.before}<mark className="error-token">{parts.token}</mark>{parts.after}
+                </div>
+            }
         </div>
     )
-}
\ No newline at end of file
+}
@@ -19,7 +19,7 @@
 import { useRef, useState } from "react"
 
 export type TokenType = "operator" | "literal_num" | "unit"
-export type UiAction = "append_space" | "append_newline" | "backspace" | "submit" | "clear"
+export type UiAction = "append_space" | "append_newline" | "backspace" | "submit" | "clear" | "undo" | "redo"
 
 export interface KeyboardProps {
     onUiAction: (action: UiAction) => void
@@ -75,6 +75,7 @@ export function Keyboard(props: KeyboardProps) {
                     })
                 }
                 <UiActionButton action="append_space" text="␣" />
+                <UiActionButton action="undo" text="↶" />
             </div>
             <div className="keyboard-col">
                 <div className="keyboard-key" data-tokentype="operator">d</div>
@@ -87,6 +88,7 @@ export function Keyboard(props: KeyboardProps) {
                 }
 
                 <UiActionButton action="backspace" text="←" />
+                <UiActionButton action="redo" text="↷" />
             </div>
 
             <div className="keyboard-col">
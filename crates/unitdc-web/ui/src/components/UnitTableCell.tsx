@@ -0,0 +1,45 @@
+/**
+ * Copyright 2024 eternal-flame-AD <yume@yumechi.jp>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+import { UnitTable } from "../types";
+
+export interface UnitTableCellProps {
+    table: UnitTable
+}
+
+export function UnitTableCell(props: UnitTableCellProps) {
+    return (
+        <div className="unitdc-io output unit-table">
+            <label className="prompt">Base units:</label>
+            <div>{props.table.base_units.map((u) => u.symbol).join(", ")}</div>
+            <label className="prompt">Derived units:</label>
+            <table>
+                <tbody>
+                    {props.table.derived_units.map((u) => (
+                        <tr key={u.symbol}>
+                            <td>{u.symbol}</td>
+                            <td>{u.exponents.map((e) => e.exponent === 1 ? e.unit.symbol : `${e.unit.symbol}^${e.exponent}`).join("*")}</td>
+                        </tr>
+                    ))}
+                </tbody>
+            </table>
+            <label className="prompt">Prefixes:</label>
+            <div>{props.table.prefixes.map(([p]) => p).join(", ")}</div>
+        </div>
+    )
+}
@@ -0,0 +1,40 @@
+/**
+ * Copyright 2024 eternal-flame-AD <yume@yumechi.jp>
+ *
+ * Licensed under the Apache License, Version 2.0 (the "License");
+ * you may not use this file except in compliance with the License.
+ * You may obtain a copy of the License at
+ *
+ *     http://www.apache.org/licenses/LICENSE-2.0
+ *
+ * Unless required by applicable law or agreed to in writing, software
+ * distributed under the License is distributed on an "AS IS" BASIS,
+ * WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+ * See the License for the specific language governing permissions and
+ * limitations under the License.
+ *
+ * SPDX-License-Identifier: Apache-2.0
+ */
+
+import { Warning } from "../types";
+
+export interface WarningCellProps {
+    warning: Warning
+}
+
+function describe(warning: Warning) {
+    switch (warning.kind.kind) {
+        case 'offset_units_combined':
+            return `${warning.kind.base_unit.symbol} is used in multiple quantities with an offset. This may lead to unexpected results.`;
+    }
+}
+
+export function WarningCell(props: WarningCellProps) {
+    return (
+        <div className="unitdc-io warning">
+            <label className="prompt">Warning:</label>
+            <div>{describe(props.warning)}</div>
+            <div>Affected units: {props.warning.units.join(", ")}</div>
+        </div>
+    )
+}
@@ -31,4 +31,29 @@ export interface UnitExponent {
 
 export interface Unit {
     symbol: string;
-}
\ No newline at end of file
+}
+export interface Warning {
+    kind: { kind: 'offset_units_combined', base_unit: Unit };
+    units: string[];
+}
+
+export interface UnitTable {
+    base_units: Unit[];
+    derived_units: { symbol: string, exponents: { unit: Unit, exponent: number }[] }[];
+    prefixes: [string, unknown][];
+}
+
+export interface SourcePosition {
+    line: number;
+    column: number;
+}
+
+export interface InterpreterError {
+    kind: string;
+    message: string;
+    diagnostic: string;
+    span: { start: SourcePosition, end: SourcePosition } | null;
+    token: string | null;
+    file: string | null;
+    [payload: string]: unknown;
+}
@@ -0,0 +1,126 @@
+// Copyright 2024 eternal-flame-AD <yume@yumechi.jp>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::quantity::units::UnitSystem;
+
+use super::{output::OutputSink, value::Value, Interpreter};
+
+/// The default number of snapshots kept for undo.
+pub const DEFAULT_HISTORY_LIMIT: usize = 100;
+
+/// A copy of the interpreter state that undo and redo switch between.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub variables: HashMap<String, Value>,
+    pub macros: HashMap<String, String>,
+    pub unit_system: UnitSystem,
+    pub stack: Vec<Value>,
+}
+
+/// Bounded undo and redo stacks of [`Snapshot`]s.
+#[derive(Debug, Clone)]
+pub struct History {
+    pub(super) undo: Vec<Snapshot>,
+    pub(super) redo: Vec<Snapshot>,
+    pub(super) limit: usize,
+    /// Set when undo or redo ran during the current input, so the input itself is not recorded.
+    pub(super) moved: bool,
+}
+
+impl Default for History {
+    fn default() -> Self {
+        History {
+            undo: Vec::new(),
+            redo: Vec::new(),
+            limit: DEFAULT_HISTORY_LIMIT,
+            moved: false,
+        }
+    }
+}
+
+impl<S: OutputSink> Interpreter<S> {
+    /// Takes a snapshot of the stack, variables, macros and unit system.
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            variables: self.variables.clone(),
+            macros: self.macros.clone(),
+            unit_system: self.unit_system.clone(),
+            stack: self.stack.clone(),
+        }
+    }
+    /// Replaces the stack, variables, macros and unit system with a snapshot.
+    pub fn restore(&mut self, snapshot: Snapshot) {
+        self.variables = snapshot.variables;
+        self.macros = snapshot.macros;
+        self.unit_system = snapshot.unit_system;
+        self.stack = snapshot.stack;
+    }
+    /// Records the state before an input, if the input changed anything, and clears the redo history.
+    pub(super) fn record_history(&mut self, before: Snapshot) {
+        if self.history.moved || self.history.limit == 0 || before == self.snapshot() {
+            return;
+        }
+        self.history.redo.clear();
+        self.history.undo.push(before);
+        if self.history.undo.len() > self.history.limit {
+            let excess = self.history.undo.len() - self.history.limit;
+            self.history.undo.drain(..excess);
+        }
+    }
+    /// Reverts the last input that changed the state. Returns `false` if there is nothing to undo.
+    pub fn undo(&mut self) -> bool {
+        match self.history.undo.pop() {
+            Some(snapshot) => {
+                self.history.redo.push(self.snapshot());
+                self.restore(snapshot);
+                self.history.moved = true;
+                true
+            }
+            None => false,
+        }
+    }
+    /// Re-applies the last undone input. Returns `false` if there is nothing to redo.
+    pub fn redo(&mut self) -> bool {
+        match self.history.redo.pop() {
+            Some(snapshot) => {
+                self.history.undo.push(self.snapshot());
+                self.restore(snapshot);
+                self.history.moved = true;
+                true
+            }
+            None => false,
+        }
+    }
+    /// The maximum number of inputs that can be undone.
+    pub fn history_limit(&self) -> usize {
+        self.history.limit
+    }
+    pub fn set_history_limit(&mut self, limit: usize) {
+        self.history.limit = limit;
+        if self.history.undo.len() > limit {
+            let excess = self.history.undo.len() - limit;
+            self.history.undo.drain(..excess);
+        }
+        self.history.redo.truncate(limit);
+    }
+    /// Forgets all undo and redo history.
+    pub fn clear_history(&mut self) {
+        self.history.undo.clear();
+        self.history.redo.clear();
+    }
+}
@@ -0,0 +1,185 @@
+// Copyright 2024 eternal-flame-AD <yume@yumechi.jp>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+};
+
+use thiserror::Error;
+
+use super::{output::OutputSink, Interpreter, InterpreterError, InterpreterResult};
+
+#[derive(Debug, Error)]
+pub enum IncludeError {
+    #[error("File not found: {0}")]
+    NotFound(String),
+    #[error("Cannot read {path}: {source}")]
+    Io {
+        path: String,
+        source: std::io::Error,
+    },
+    #[error("Include cycle: {}", .0.join(" -> "))]
+    Cycle(Vec<String>),
+    #[error("Includes are disabled, cannot include {0}")]
+    Disabled(String),
+}
+
+/// Finds and reads the files named in `@include`.
+pub trait IncludeResolver {
+    /// Resolves `path` as written in an `@include` to a name that uniquely identifies the file.
+    ///
+    /// `from` is the name of the including file, or `None` for input that does not come from a file.
+    fn resolve(&self, from: Option<&str>, path: &str) -> Result<String, IncludeError>;
+    /// Reads the contents of a file by a name returned from [`IncludeResolver::resolve`].
+    fn load(&self, name: &str) -> Result<String, IncludeError>;
+}
+
+/// Refuses all includes. This is the default, so that untrusted input cannot read files.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoResolver;
+
+impl IncludeResolver for NoResolver {
+    fn resolve(&self, _from: Option<&str>, path: &str) -> Result<String, IncludeError> {
+        Err(IncludeError::Disabled(path.to_string()))
+    }
+    fn load(&self, name: &str) -> Result<String, IncludeError> {
+        Err(IncludeError::Disabled(name.to_string()))
+    }
+}
+
+/// Reads includes from the filesystem, relative to the including file or to the working directory.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FsResolver;
+
+impl IncludeResolver for FsResolver {
+    fn resolve(&self, from: Option<&str>, path: &str) -> Result<String, IncludeError> {
+        let base = from
+            .and_then(|from| Path::new(from).parent())
+            .unwrap_or(Path::new(""));
+        let path = base.join(path);
+        let canonical = path.canonicalize().map_err(|source| IncludeError::Io {
+            path: path.display().to_string(),
+            source,
+        })?;
+        Ok(canonical.display().to_string())
+    }
+    fn load(&self, name: &str) -> Result<String, IncludeError> {
+        std::fs::read_to_string(name).map_err(|source| IncludeError::Io {
+            path: name.to_string(),
+            source,
+        })
+    }
+}
+
+/// Serves includes from files held in memory, for when there is no filesystem.
+///
+/// Names are `/`-separated paths, resolved relative to the including file with `.` and `..` handled.
+#[derive(Debug, Clone, Default)]
+pub struct MapResolver {
+    files: HashMap<String, String>,
+}
+
+impl MapResolver {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Adds a file, replacing any file of the same name.
+    pub fn insert(&mut self, name: &str, contents: String) {
+        self.files.insert(normalize(Path::new(name)), contents);
+    }
+}
+
+impl FromIterator<(String, String)> for MapResolver {
+    fn from_iter<T: IntoIterator<Item = (String, String)>>(iter: T) -> Self {
+        let mut resolver = Self::new();
+        for (name, contents) in iter {
+            resolver.insert(&name, contents);
+        }
+        resolver
+    }
+}
+
+/// Joins the components of a path with `/`, dropping any root and resolving `.` and `..` lexically.
+fn normalize(path: &Path) -> String {
+    let mut parts = PathBuf::new();
+    for component in path.components() {
+        match component {
+            std::path::Component::ParentDir => {
+                parts.pop();
+            }
+            std::path::Component::Normal(part) => parts.push(part),
+            _ => {}
+        }
+    }
+    parts
+        .iter()
+        .map(|part| part.to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+impl IncludeResolver for MapResolver {
+    fn resolve(&self, from: Option<&str>, path: &str) -> Result<String, IncludeError> {
+        let base = from
+            .and_then(|from| Path::new(from).parent())
+            .unwrap_or(Path::new(""));
+        let name = normalize(&base.join(path));
+        if !self.files.contains_key(&name) {
+            return Err(IncludeError::NotFound(path.to_string()));
+        }
+        Ok(name)
+    }
+    fn load(&self, name: &str) -> Result<String, IncludeError> {
+        self.files
+            .get(name)
+            .cloned()
+            .ok_or_else(|| IncludeError::NotFound(name.to_string()))
+    }
+}
+
+impl<S: OutputSink> Interpreter<S> {
+    /// Replaces how `@include` finds files. By default, includes are refused with [`NoResolver`].
+    pub fn set_include_resolver(&mut self, resolver: Box<dyn IncludeResolver + Send>) {
+        self.include_resolver = resolver;
+    }
+    /// Runs a file as part of the input currently being processed.
+    ///
+    /// Relative paths are resolved against the file being included from, if any. Including a file that is
+    /// already being included fails with [`IncludeError::Cycle`].
+    pub fn include(&mut self, path: &str) -> InterpreterResult<()> {
+        let name = self
+            .include_resolver
+            .resolve(self.include_stack.last().map(String::as_str), path)
+            .map_err(InterpreterError::IncludeError)?;
+        if self.include_stack.contains(&name) {
+            let mut cycle = self.include_stack.clone();
+            cycle.push(name);
+            return Err(InterpreterError::IncludeError(IncludeError::Cycle(cycle)));
+        }
+        let contents = self
+            .include_resolver
+            .load(&name)
+            .map_err(InterpreterError::IncludeError)?;
+
+        self.include_stack.push(name.clone());
+        let result = self.run_nested(&contents);
+        self.include_stack.pop();
+
+        result.map_err(|error| InterpreterError::InFile {
+            path: name,
+            error: Box::new(error),
+        })
+    }
+}
@@ -0,0 +1,175 @@
+// Copyright 2024 eternal-flame-AD <yume@yumechi.jp>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::fmt::Display;
+
+use num_rational::BigRational;
+use serde::Serialize;
+
+use super::{output::OutputSink, value::Value, Interpreter, InterpreterError, InterpreterResult};
+
+/// Limits that keep a single input from hanging the interpreter or exhausting memory.
+///
+/// `None` disables a limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// The largest exponent, in magnitude, that may be written in a number literal, as in `1e4096`.
+    pub max_literal_exponent: Option<u64>,
+    /// The largest size in bits of the numerator or denominator of any number on the stack.
+    pub max_number_bits: Option<u64>,
+    /// The most tokens a single call to [`Interpreter::run_str`] may process.
+    pub max_tokens: Option<usize>,
+    /// How deeply macros may execute other macros.
+    pub max_macro_depth: Option<usize>,
+    /// The most times a single loop may run its body.
+    pub max_loop_iterations: Option<usize>,
+    /// The largest root that `^` may take, as in `2 1|3 ^`, and the largest unit exponent, in magnitude, it may produce.
+    pub max_exponent: Option<u64>,
+    /// The largest output precision that may be set with `k`.
+    pub max_precision: Option<u64>,
+    /// The most values the stack may hold.
+    pub max_stack_depth: Option<usize>,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_literal_exponent: Some(4096),
+            max_number_bits: Some(65536),
+            max_tokens: Some(1_000_000),
+            max_macro_depth: Some(64),
+            max_loop_iterations: Some(10_000),
+            max_exponent: Some(1000),
+            max_precision: Some(4096),
+            max_stack_depth: Some(100_000),
+        }
+    }
+}
+
+impl Limits {
+    /// No limits at all.
+    pub fn unlimited() -> Self {
+        Limits {
+            max_literal_exponent: None,
+            max_number_bits: None,
+            max_tokens: None,
+            max_macro_depth: None,
+            max_loop_iterations: None,
+            max_exponent: None,
+            max_precision: None,
+            max_stack_depth: None,
+        }
+    }
+}
+
+/// The resource a [`Limits`] entry applies to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Limit {
+    LiteralExponent,
+    NumberBits,
+    Tokens,
+    MacroDepth,
+    LoopIterations,
+    Exponent,
+    Precision,
+    StackDepth,
+}
+
+impl Display for Limit {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Limit::LiteralExponent => write!(f, "literal exponent"),
+            Limit::NumberBits => write!(f, "number size in bits"),
+            Limit::Tokens => write!(f, "number of tokens"),
+            Limit::MacroDepth => write!(f, "macro nesting depth"),
+            Limit::LoopIterations => write!(f, "loop iterations"),
+            Limit::Exponent => write!(f, "exponent"),
+            Limit::Precision => write!(f, "output precision"),
+            Limit::StackDepth => write!(f, "stack depth"),
+        }
+    }
+}
+
+/// The larger of the sizes in bits of the numerator and denominator.
+pub(super) fn number_bits(n: &BigRational) -> u64 {
+    n.numer().bits().max(n.denom().bits())
+}
+
+impl<S: OutputSink> Interpreter<S> {
+    /// The resource limits applied to each input.
+    pub fn limits(&self) -> Limits {
+        self.limits
+    }
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+    /// Fails if a number of `bits` bits would exceed [`Limits::max_number_bits`].
+    pub(super) fn check_number_bits(&self, bits: u64) -> InterpreterResult<()> {
+        match self.limits.max_number_bits {
+            Some(max) if bits > max => Err(InterpreterError::LimitExceeded {
+                limit: Limit::NumberBits,
+                max,
+            }),
+            _ => Ok(()),
+        }
+    }
+    /// Fails if `exponent` exceeds [`Limits::max_exponent`].
+    pub(super) fn check_exponent(&self, exponent: u64) -> InterpreterResult<()> {
+        match self.limits.max_exponent {
+            Some(max) if exponent > max => Err(InterpreterError::LimitExceeded {
+                limit: Limit::Exponent,
+                max,
+            }),
+            _ => Ok(()),
+        }
+    }
+    /// Fails if `precision` exceeds [`Limits::max_precision`].
+    pub(super) fn check_precision(&self, precision: u64) -> InterpreterResult<()> {
+        match self.limits.max_precision {
+            Some(max) if precision > max => Err(InterpreterError::LimitExceeded {
+                limit: Limit::Precision,
+                max,
+            }),
+            _ => Ok(()),
+        }
+    }
+    /// Fails if any number among `values`, including the scales and offsets of the derived units quantities are
+    /// displayed in, exceeds [`Limits::max_number_bits`].
+    pub(super) fn check_value_limits<'v>(
+        &self,
+        values: impl IntoIterator<Item = &'v Value>,
+    ) -> InterpreterResult<()> {
+        values.into_iter().try_for_each(|v| match v {
+            Value::Quantity(q) => {
+                self.check_number_bits(number_bits(&q.number))?;
+                q.use_derived_unit.iter().try_for_each(|d| {
+                    self.check_number_bits(number_bits(&d.scale))?;
+                    self.check_number_bits(number_bits(&d.offset))
+                })
+            }
+            Value::String(_) => Ok(()),
+        })
+    }
+    /// Fails if a stack of `depth` values would exceed [`Limits::max_stack_depth`].
+    pub(super) fn check_stack_depth(&self, depth: usize) -> InterpreterResult<()> {
+        match self.limits.max_stack_depth {
+            Some(max) if depth > max => Err(InterpreterError::LimitExceeded {
+                limit: Limit::StackDepth,
+                max: max as u64,
+            }),
+            _ => Ok(()),
+        }
+    }
+}
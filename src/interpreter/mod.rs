@@ -12,55 +12,102 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::{
-    collections::HashMap,
-    io::{BufReader, Read},
-};
+use std::collections::HashMap;
 
 use crate::{
     quantity::{
+        format::NumberFormat,
+        unit_expr::UnitExprError,
         units::{UnitCombo, UnitSystem},
         Quantity, QuantityError,
     },
-    tokenizer::{token::Token, ReaderCursor, Tokenizer, TokenizerError},
+    tokenizer::{token::Token, Span, SpannedToken, TokenSource, Tokenizer, TokenizerError},
 };
 
+use history::History;
+use include::{IncludeError, IncludeResolver, NoResolver};
 use itertools::Itertools;
+use limits::{Limit, Limits};
 use num_bigint::BigInt;
-use num_traits::Zero;
+use num_traits::{ToPrimitive, Zero};
+use output::{Definition, OutputSink, SolverReport, UnitTable, Warning, WarningKind};
+use serde::{ser::SerializeStruct, Serialize, Serializer};
 use thiserror::Error;
+use value::Value;
 
+/// Undo and redo history.
+pub mod history;
+/// Including other input files.
+pub mod include;
+/// Resource limits.
+pub mod limits;
 /// All other operations.
 pub mod ops;
 /// Macro operations.
 pub mod ops_macros;
+/// Stack manipulation operations.
+pub mod ops_stack;
 /// Variable I/O operations.
 pub mod ops_variables;
+/// Where output is sent.
+pub mod output;
+/// Saving and restoring the interpreter state.
+pub mod session;
+/// Values on the stack and in variables.
+pub mod value;
 
-pub struct Interpreter<'a> {
-    variables: HashMap<String, Quantity>,
+pub struct Interpreter<S> {
+    variables: HashMap<String, Value>,
+    macros: HashMap<String, String>,
     unit_system: UnitSystem,
-    stack: Vec<Quantity>,
-    output: Box<dyn Fn(Output) + 'a>,
+    stack: Vec<Value>,
+    number_format: NumberFormat,
+    auto_unit: bool,
+    history: History,
+    atomic: bool,
+    limits: Limits,
+    /// Tokens processed so far in the current input, including those run by macros.
+    tokens_run: usize,
+    /// How many macros are currently executing inside each other.
+    macro_depth: usize,
+    include_resolver: Box<dyn IncludeResolver + Send>,
+    /// Names of the files currently being included, innermost last.
+    include_stack: Vec<String>,
+    sink: S,
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "type", content = "value", rename_all = "snake_case")]
 pub enum Output {
     Quantity(Quantity),
     QuantityList(Vec<Quantity>),
     Message(String),
+    Warning(Warning),
+    UnitTable(UnitTable),
+    SolverReport(SolverReport),
+    /// A value was stored in a variable.
+    VariableAssigned {
+        name: String,
+        value: Value,
+    },
+    UnitDefined(Definition),
+    MacroDefined {
+        name: String,
+    },
 }
 
 #[derive(Debug, Error)]
 pub enum InterpreterError {
     #[error("Tokenizer error: {1} at {0}")]
-    TokenizerError(ReaderCursor, TokenizerError),
+    TokenizerError(Span, TokenizerError),
     #[error("Quantity error: {0}")]
     QuantityError(QuantityError),
-    #[error("Stack underflow")]
-    StackUnderflow,
+    #[error("Stack underflow: needed {needed} items, but only {present} present")]
+    StackUnderflow { needed: usize, present: usize },
     #[error("Undefined unit: {0}")]
     UndefinedUnit(String),
+    #[error("Invalid unit expression: {0}")]
+    UnitExprError(UnitExprError),
     #[error("Undefined macro: {0}")]
     UndefinedMacro(String),
     #[error("Undefined variable: {0}")]
@@ -71,21 +118,282 @@ pub enum InterpreterError {
     NoSolution(String),
     #[error("Already defined: {0}")]
     AlreadyDefined(String),
+    #[error("Invalid argument: {0}")]
+    InvalidArgument(String),
+    #[error("Nothing to {0}")]
+    NoHistory(String),
+    #[error("Type mismatch: expected a {expected}, got {found}")]
+    TypeMismatch { expected: String, found: String },
+    #[error("Invalid session: {0}")]
+    InvalidSession(String),
+    #[error("Limit exceeded: {limit} is limited to {max}")]
+    LimitExceeded { limit: Limit, max: u64 },
+    #[error("Include error: {0}")]
+    IncludeError(IncludeError),
+    #[error("{error} (in {path})")]
+    InFile {
+        path: String,
+        error: Box<InterpreterError>,
+    },
+    #[error("{error} (at token {index}: {token}, {span})")]
+    AtToken {
+        index: usize,
+        token: String,
+        span: Span,
+        error: Box<InterpreterError>,
+    },
+}
+
+impl InterpreterError {
+    /// The underlying error, without the token it occurred at.
+    pub fn inner(&self) -> &InterpreterError {
+        match self {
+            InterpreterError::AtToken { error, .. } | InterpreterError::InFile { error, .. } => {
+                error.inner()
+            }
+            e => e,
+        }
+    }
+    /// Where in the input the error occurred, if known.
+    pub fn span(&self) -> Option<Span> {
+        match self {
+            InterpreterError::TokenizerError(span, _) | InterpreterError::AtToken { span, .. } => {
+                Some(*span)
+            }
+            _ => None,
+        }
+    }
+    /// Renders the error message followed by the offending line of `input` with the failing token underlined.
+    pub fn render_diagnostic(&self, input: &str) -> String {
+        match self.span() {
+            Some(span) => format!("{}\n{}", self, span.render(input)),
+            None => self.to_string(),
+        }
+    }
+    /// A stable code for the kind of the underlying error, such as `undefined_unit`.
+    pub fn kind(&self) -> &'static str {
+        match self.inner() {
+            InterpreterError::TokenizerError(..) => "tokenizer_error",
+            InterpreterError::QuantityError(_) => "quantity_error",
+            InterpreterError::StackUnderflow { .. } => "stack_underflow",
+            InterpreterError::UndefinedUnit(_) => "undefined_unit",
+            InterpreterError::UnitExprError(_) => "unit_expr_error",
+            InterpreterError::UndefinedMacro(_) => "undefined_macro",
+            InterpreterError::UndefinedVariable(_) => "undefined_variable",
+            InterpreterError::IncompatibleUnits(_) => "incompatible_units",
+            InterpreterError::NoSolution(_) => "no_solution",
+            InterpreterError::AlreadyDefined(_) => "already_defined",
+            InterpreterError::InvalidArgument(_) => "invalid_argument",
+            InterpreterError::NoHistory(_) => "no_history",
+            InterpreterError::TypeMismatch { .. } => "type_mismatch",
+            InterpreterError::InvalidSession(_) => "invalid_session",
+            InterpreterError::LimitExceeded { .. } => "limit_exceeded",
+            InterpreterError::IncludeError(_) => "include_error",
+            InterpreterError::InFile { .. } | InterpreterError::AtToken { .. } => {
+                unreachable!("inner() unwraps these")
+            }
+        }
+    }
+    /// The file the underlying error occurred in, if it happened in an included file.
+    pub fn file(&self) -> Option<&str> {
+        match self {
+            InterpreterError::AtToken { error, .. } => error.file(),
+            InterpreterError::InFile { path, error } => error.file().or(Some(path)),
+            _ => None,
+        }
+    }
+}
+
+/// Serializes as an object with the [`InterpreterError::kind`] code, the full `message`, the `span` and `token`
+/// of the failing token in the input if known, the `file` if the error occurred in an included file, and
+/// fields specific to the kind of error, such as `unit` for `undefined_unit` or `units` for `incompatible_units`.
+impl Serialize for InterpreterError {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut s = serializer.serialize_struct("InterpreterError", 7)?;
+        s.serialize_field("kind", self.kind())?;
+        s.serialize_field("message", &self.to_string())?;
+        s.serialize_field("span", &self.span())?;
+        match self {
+            InterpreterError::AtToken { token, .. } => s.serialize_field("token", token)?,
+            _ => s.serialize_field("token", &None::<String>)?,
+        }
+        s.serialize_field("file", &self.file())?;
+        match self.inner() {
+            InterpreterError::TokenizerError(_, e) => s.serialize_field("cause", &e.to_string())?,
+            InterpreterError::QuantityError(e) => s.serialize_field("cause", e)?,
+            InterpreterError::StackUnderflow { needed, present } => {
+                s.serialize_field("needed", needed)?;
+                s.serialize_field("present", present)?;
+            }
+            InterpreterError::UndefinedUnit(unit) => s.serialize_field("unit", unit)?,
+            InterpreterError::UnitExprError(e) => s.serialize_field("cause", e)?,
+            InterpreterError::UndefinedMacro(name)
+            | InterpreterError::UndefinedVariable(name)
+            | InterpreterError::AlreadyDefined(name) => s.serialize_field("name", name)?,
+            InterpreterError::IncompatibleUnits(units) => s.serialize_field("units", units)?,
+            InterpreterError::NoSolution(reason)
+            | InterpreterError::InvalidArgument(reason)
+            | InterpreterError::InvalidSession(reason) => s.serialize_field("reason", reason)?,
+            InterpreterError::NoHistory(action) => s.serialize_field("action", action)?,
+            InterpreterError::TypeMismatch { expected, found } => {
+                s.serialize_field("expected", expected)?;
+                s.serialize_field("found", found)?;
+            }
+            InterpreterError::LimitExceeded { limit, max } => {
+                s.serialize_field("limit", limit)?;
+                s.serialize_field("max", max)?;
+            }
+            InterpreterError::IncludeError(e) => s.serialize_field("cause", &e.to_string())?,
+            InterpreterError::InFile { .. } | InterpreterError::AtToken { .. } => {}
+        }
+        s.end()
+    }
 }
 
 pub type InterpreterResult<T> = Result<T, InterpreterError>;
 
-impl<'a> Interpreter<'a> {
-    pub fn new(output: Box<dyn Fn(Output) + 'a>) -> Self {
+impl<S: OutputSink> Interpreter<S> {
+    pub fn new(sink: S) -> Self {
         Self {
             variables: HashMap::new(),
+            macros: HashMap::new(),
             unit_system: UnitSystem::new(),
             stack: Vec::new(),
-            output,
+            number_format: NumberFormat::default(),
+            auto_unit: false,
+            history: History::default(),
+            atomic: false,
+            limits: Limits::default(),
+            tokens_run: 0,
+            macro_depth: 0,
+            include_resolver: Box::new(NoResolver),
+            include_stack: Vec::new(),
+            sink,
         }
     }
+    /// Where output is sent, for example to read the outputs collected in a [`Vec`].
+    pub fn sink(&self) -> &S {
+        &self.sink
+    }
+    pub fn sink_mut(&mut self) -> &mut S {
+        &mut self.sink
+    }
+    pub fn into_sink(self) -> S {
+        self.sink
+    }
+    /// The stack, with the top last.
+    pub fn stack(&self) -> &[Value] {
+        &self.stack
+    }
+    /// The number format applied to quantities sent to the output.
+    pub fn number_format(&self) -> NumberFormat {
+        self.number_format
+    }
+    /// Sets the number format, failing if its precision exceeds [`Limits::max_precision`].
+    pub fn set_number_format(&mut self, format: NumberFormat) -> InterpreterResult<()> {
+        self.check_precision(format.precision.into())?;
+        self.number_format = format;
+        Ok(())
+    }
+    /// Whether quantities sent to the output are automatically displayed in the most readable derived unit.
+    pub fn auto_unit(&self) -> bool {
+        self.auto_unit
+    }
+    pub fn set_auto_unit(&mut self, auto_unit: bool) {
+        self.auto_unit = auto_unit;
+    }
+    /// Whether [`Interpreter::run_str`] rolls back the stack, variables, macros and unit system when an input fails partway.
+    pub fn atomic(&self) -> bool {
+        self.atomic
+    }
+    pub fn set_atomic(&mut self, atomic: bool) {
+        self.atomic = atomic;
+    }
+    /// Attaches the current number format, and the best unit if enabled, to a quantity before it is sent to the output.
+    fn formatted(&self, mut q: Quantity) -> Quantity {
+        q.use_format = Some(self.number_format);
+        if self.auto_unit && !q.unit.is_unitless() {
+            let entered = q
+                .use_derived_unit
+                .iter()
+                .find(|u| u.exponents == q.unit)
+                .cloned();
+            q.use_derived_unit.retain(|u| u.exponents != q.unit);
+            if let Some(d) =
+                self.unit_system
+                    .best_derived_unit(&q.number, &q.unit, entered.as_ref())
+            {
+                q.use_derived_unit.push(d);
+            }
+        }
+        q
+    }
+    /// Fails with a stack underflow if the stack holds fewer than `n` items.
+    fn require(&self, n: usize) -> InterpreterResult<()> {
+        if self.stack.len() < n {
+            return Err(InterpreterError::StackUnderflow {
+                needed: n,
+                present: self.stack.len(),
+            });
+        }
+        Ok(())
+    }
+    /// Pushes a value to the stack.
+    ///
+    /// Fails if the stack would grow past [`Limits::max_stack_depth`], or the value holds a number larger than
+    /// [`Limits::max_number_bits`].
+    fn push(&mut self, v: Value) -> InterpreterResult<()> {
+        self.check_stack_depth(self.stack.len().saturating_add(1))?;
+        self.check_value_limits([&v])?;
+        self.stack.push(v);
+        Ok(())
+    }
+    /// Pops the top of the stack, whatever its type.
+    fn pop_value(&mut self) -> InterpreterResult<Value> {
+        self.require(1)?;
+        Ok(self.stack.pop().unwrap())
+    }
+    /// Pops a quantity from the top of the stack.
+    fn pop(&mut self) -> InterpreterResult<Quantity> {
+        self.pop_value()?.into_quantity()
+    }
+    /// Pops the top two quantities of the stack, returning the second item first.
+    fn pop_pair(&mut self) -> InterpreterResult<(Quantity, Quantity)> {
+        self.require(2)?;
+        let top = self.stack.pop().unwrap();
+        Ok((
+            self.stack.pop().unwrap().into_quantity()?,
+            top.into_quantity()?,
+        ))
+    }
+    /// Pops the top `n` quantities of the stack, in stack order.
+    fn pop_n(&mut self, n: usize) -> InterpreterResult<Vec<Quantity>> {
+        self.require(n)?;
+        self.stack
+            .split_off(self.stack.len() - n)
+            .into_iter()
+            .map(Value::into_quantity)
+            .collect()
+    }
+    /// Pops a unit-less, non-negative integer from the stack, such as a count or an index.
+    fn pop_count(&mut self) -> InterpreterResult<usize> {
+        let q = self.pop()?;
+
+        if !q.unit.is_unitless() {
+            return Err(InterpreterError::IncompatibleUnits(q.unit));
+        }
+        Some(&q.number)
+            .filter(|n| n.is_integer())
+            .and_then(|n| n.to_integer().to_usize())
+            .ok_or_else(|| {
+                InterpreterError::InvalidArgument(format!(
+                    "expected a non-negative integer, got {}",
+                    q
+                ))
+            })
+    }
     /// Warns about quantities with offset derived units that are used in multiple quantities.
-    pub fn warn_confusing_unit_conversions(&self, qs: &[&Quantity]) {
+    pub fn warn_confusing_unit_conversions(&mut self, qs: &[&Quantity]) {
         let offset_base_units = qs
             .iter()
             .flat_map(|q| q.use_derived_unit.iter())
@@ -100,56 +408,133 @@ impl<'a> Interpreter<'a> {
 
         for (u, c) in offset_base_units.into_group_map_by(|(_, u)| u.clone()) {
             if c.len() > 1 {
-                (self.output)(Output::Message(format!(
-                    "Warning: {} it is used in multiple quantities with an offset. This may lead to unexpected results. Affected derived units: [{}]",
-                    u,
-                    c.iter().map(|(d, _)| d.symbol.clone()).join(", ")
-                )));
+                self.sink.emit(Output::Warning(Warning {
+                    kind: WarningKind::OffsetUnitsCombined { base_unit: u },
+                    units: c.into_iter().map(|(d, _)| d.symbol).collect(),
+                }));
             }
         }
     }
     /// Reads all tokens from the tokenizer and processes them.
-    pub fn process_tokens<R: Read>(
-        &mut self,
-        tokenizer: &mut Tokenizer<R>,
-    ) -> InterpreterResult<()> {
-        while let Some(token) = tokenizer
-            .parse_next_token()
-            .map_err(|e| InterpreterError::TokenizerError(tokenizer.get_cursor(), e))?
-        {
-            match token {
-                Token::Number(n) => self.op_number(n)?,
-                Token::Unit(u) => self.op_unit(&u)?,
-                Token::Add => self.op_add()?,
-                Token::Sub => self.op_sub()?,
-                Token::Mul => self.op_mul()?,
-                Token::Div => self.op_div()?,
-                Token::Operator('p') => self.op_p()?,
-                Token::Operator('n') => self.op_n()?,
-                Token::Operator('f') => self.op_f()?,
-                Token::Operator('c') => self.op_c()?,
-                Token::Operator('d') => self.op_d()?,
-                Token::Operator('r') => self.op_r()?,
-                Token::Operator('s') => self.op_s()?,
-                Token::Operator('U') => self.op_upper_u()?,
-                Token::VarRecall(name) => self.op_recall(&name)?,
-                Token::VarStore(name) => self.op_store(&name)?,
-                Token::MacroInvoke((name, args)) => match name.as_str() {
-                    "base" => self.op_macro_baseunit(&args)?,
-                    "derived" => self.op_macro_derivedunit(&args)?,
-                    _ => return Err(InterpreterError::UndefinedMacro(name)),
+    ///
+    /// Errors are reported with the index and span of the failing token. Processing stops with
+    /// [`InterpreterError::LimitExceeded`] once a number grows past [`Limits::max_number_bits`], the stack grows
+    /// past [`Limits::max_stack_depth`], or after [`Limits::max_tokens`] tokens, counting the tokens run by macros.
+    pub fn process_tokens<T: TokenSource>(&mut self, tokenizer: &mut T) -> InterpreterResult<()> {
+        if self.macro_depth == 0 {
+            self.tokens_run = 0;
+        }
+        let mut index = 0;
+        while let Some(SpannedToken { token, span }) =
+            tokenizer.parse_next_spanned_token().map_err(|e| match e {
+                TokenizerError::ExponentTooLarge(literal) => InterpreterError::AtToken {
+                    index,
+                    token: literal,
+                    span: tokenizer.current_span(),
+                    error: Box::new(InterpreterError::LimitExceeded {
+                        limit: Limit::LiteralExponent,
+                        max: self.limits.max_literal_exponent.unwrap_or_default(),
+                    }),
                 },
-                Token::Comment(_) => {}
-                _ => eprintln!("Unhandled token: {:?}", token),
-            }
+                e => InterpreterError::TokenizerError(tokenizer.current_span(), e),
+            })?
+        {
+            let result = match self.limits.max_tokens {
+                Some(max) if self.tokens_run >= max => Err(InterpreterError::LimitExceeded {
+                    limit: Limit::Tokens,
+                    max: max as u64,
+                }),
+                _ => {
+                    self.tokens_run += 1;
+                    self.process_token(&token)
+                }
+            };
+            result.map_err(|error| InterpreterError::AtToken {
+                index,
+                token: token.to_string(),
+                span,
+                error: Box::new(error),
+            })?;
+            index += 1;
         }
 
         Ok(())
     }
-    pub fn run_str(&mut self, input: &str) -> InterpreterResult<()> {
-        let mut tokenizer = Tokenizer::new(BufReader::new(input.as_bytes()));
-        self.process_tokens(&mut tokenizer)?;
+    /// Processes a single token.
+    pub fn process_token(&mut self, token: &Token) -> InterpreterResult<()> {
+        match token {
+            Token::Number(n) => self.op_number(n.clone())?,
+            Token::String(s) => self.op_string(s)?,
+            Token::Unit(u) => self.op_unit(u)?,
+            Token::Add => self.op_add()?,
+            Token::Sub => self.op_sub()?,
+            Token::Mul => self.op_mul()?,
+            Token::Div => self.op_div()?,
+            Token::Pow => self.op_pow()?,
+            Token::Neg => self.op_neg()?,
+            Token::Operator('p') => self.op_p()?,
+            Token::Operator('n') => self.op_n()?,
+            Token::Operator('f') => self.op_f()?,
+            Token::Operator('c') => self.op_c()?,
+            Token::Operator('d') => self.op_d()?,
+            Token::Operator('r') => self.op_r()?,
+            Token::Operator('D') => self.op_upper_d()?,
+            Token::Operator('t') => self.op_t()?,
+            Token::Operator('y') => self.op_y()?,
+            Token::Operator('R') => self.op_upper_r()?,
+            Token::Operator('z') => self.op_z()?,
+            Token::Operator('Y') => self.op_upper_y()?,
+            Token::Operator('s') => self.op_s()?,
+            Token::Operator('U') => self.op_upper_u()?,
+            Token::Operator('v') => self.op_v()?,
+            Token::Operator('k') => self.op_k()?,
+            Token::Operator('K') => self.op_upper_k()?,
+            Token::Operator('x') => self.op_x()?,
+            Token::Operator('<') => self.op_lt()?,
+            Token::Operator('>') => self.op_gt()?,
+            Token::Operator('=') => self.op_eq()?,
+            Token::Operator('?') => self.op_cond()?,
+            Token::Operator('L') => self.op_upper_l()?,
+            Token::VarRecall(name) => self.op_recall(name)?,
+            Token::VarStore(name) => self.op_store(name)?,
+            Token::MacroInvoke((name, args)) => match name.as_str() {
+                "base" => self.op_macro_baseunit(args)?,
+                "derived" => self.op_macro_derivedunit(args)?,
+                "prefix" => self.op_macro_prefix(args)?,
+                "noprefix" => self.op_macro_noprefix(args)?,
+                "format" => self.op_macro_format(args)?,
+                "autounit" => self.op_macro_autounit(args)?,
+                "undo" => self.op_macro_undo(args)?,
+                "redo" => self.op_macro_redo(args)?,
+                "def" => self.op_macro_def(args)?,
+                "include" => self.op_macro_include(args)?,
+                _ => self.op_macro_user(name, args)?,
+            },
+            Token::Comment(_) => {}
+            _ => eprintln!("Unhandled token: {:?}", token),
+        }
 
         Ok(())
     }
+    /// Runs a string of input. The state before the input is recorded for [`Interpreter::undo`].
+    ///
+    /// If the input fails partway, the tokens before the failure stay in effect, unless [`Interpreter::atomic`]
+    /// is set, in which case the stack, variables, macros and unit system are restored to their state before the input.
+    /// Output that was already produced is not taken back.
+    pub fn run_str(&mut self, input: &str) -> InterpreterResult<()> {
+        let before = self.snapshot();
+        self.history.moved = false;
+
+        let mut tokenizer =
+            Tokenizer::new(input).with_max_literal_exponent(self.limits.max_literal_exponent);
+        let result = self.process_tokens(&mut tokenizer);
+
+        if result.is_err() && self.atomic {
+            self.restore(before);
+            return result;
+        }
+        self.record_history(before);
+
+        result
+    }
 }
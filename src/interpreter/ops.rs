@@ -12,23 +12,37 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::cmp::Ordering;
+
+use itertools::Itertools;
 use num_rational::BigRational;
-use num_traits::{FromPrimitive, ToPrimitive};
+use num_traits::{FromPrimitive, ToPrimitive, Zero};
 
 use crate::{
     linear_system::{transpose, LinearSystem},
     quantity::{
+        unit_expr::UnitExprError,
         units::{Unit, UnitCombo},
-        Quantity,
+        Quantity, QuantityError,
     },
 };
 
-use super::{Interpreter, InterpreterError, InterpreterResult, Output};
+use super::{
+    limits::number_bits,
+    output::{OutputSink, SolverReport, UnitTable},
+    value::Value,
+    Interpreter, InterpreterError, InterpreterResult, Output,
+};
 
-impl<'a> Interpreter<'a> {
+impl<S: OutputSink> Interpreter<S> {
     /// A literal number input, pushes a unit-less quantity to the stack.
     pub fn op_number(&mut self, number: BigRational) -> InterpreterResult<()> {
-        self.stack.push(Quantity::new(number, UnitCombo::new()));
+        self.push(Quantity::new(number, UnitCombo::new()).into())?;
+        Ok(())
+    }
+    /// A string literal input, pushes the string to the stack.
+    pub fn op_string(&mut self, s: &str) -> InterpreterResult<()> {
+        self.push(Value::String(s.to_string()))?;
         Ok(())
     }
     /// A literal unit input.
@@ -36,24 +50,20 @@ impl<'a> Interpreter<'a> {
     /// - If the unit is unit-less (1), the top of the stack will be converted to a unit-less quantity.
     /// - If the top of the stack is a unit-less quantity, it will be converted to the given unit.
     /// - If the top of the stack is a quantity with equivalent units, it will be converted to the given unit.
+    /// - Units that are not defined are parsed as a unit expression, such as `m/s^2` or `kg*m^2/s^2`.
     /// - Otherwise, an error will be returned.
     pub fn op_unit(&mut self, unit: &str) -> InterpreterResult<()> {
-        let mut q = self.stack.pop().ok_or(InterpreterError::StackUnderflow)?;
+        let mut q = self.pop()?;
 
         // Unitless, remove unit
         if unit == "1" {
             q.unit = UnitCombo::new();
-            self.stack.push(q);
+            self.push(q.into())?;
             return Ok(());
         }
 
-        let unit = self
-            .unit_system
-            .lookup_unit(unit)
-            .ok_or(InterpreterError::UndefinedUnit(unit.to_string()))?;
-
-        match unit {
-            Unit::Base(base_unit) => {
+        let derived_unit = match self.unit_system.lookup_unit(unit) {
+            Some(Unit::Base(base_unit)) => {
                 let mut new_unit = UnitCombo::new();
                 new_unit.push_base_unit(base_unit.clone(), 1);
                 if q.unit.is_unitless() {
@@ -63,107 +73,217 @@ impl<'a> Interpreter<'a> {
                 } else {
                     return Err(InterpreterError::IncompatibleUnits(q.unit));
                 }
+                self.push(q.into())?;
+                return Ok(());
             }
-            Unit::Derived(derived_unit) => {
-                let mut new_unit = UnitCombo::new();
-                new_unit.push_derived_unit(derived_unit.clone());
-                if q.unit == new_unit {
-                    q.use_derived_unit
-                        .retain(|u| u.exponents != derived_unit.exponents);
-                    q.use_derived_unit.push(derived_unit.clone());
-                } else if q.unit.is_unitless() {
-                    q.number *= derived_unit.scale.clone();
-                    q.number += derived_unit.offset.clone();
-                    q.unit = new_unit;
-                    q.use_derived_unit
-                        .retain(|u| u.exponents != derived_unit.exponents);
-                    q.use_derived_unit.push(derived_unit.clone());
-                } else {
-                    return Err(InterpreterError::IncompatibleUnits(q.unit));
-                }
-            }
+            Some(Unit::Derived(derived_unit)) => derived_unit.into_owned(),
+            None => self
+                .unit_system
+                .parse_unit_expr(unit, &self.limits)
+                .map_err(|e| match e {
+                    UnitExprError::UnknownUnit(u) => InterpreterError::UndefinedUnit(u),
+                    e => InterpreterError::UnitExprError(e),
+                })?,
+        };
+
+        let mut new_unit = UnitCombo::new();
+        new_unit.push_derived_unit(derived_unit.clone());
+        if q.unit == new_unit {
+            q.use_derived_unit
+                .retain(|u| u.exponents != derived_unit.exponents);
+            q.use_derived_unit.push(derived_unit);
+        } else if q.unit.is_unitless() {
+            q.number *= derived_unit.scale.clone();
+            q.number += derived_unit.offset.clone();
+            q.unit = new_unit;
+            q.use_derived_unit
+                .retain(|u| u.exponents != derived_unit.exponents);
+            q.use_derived_unit.push(derived_unit);
+        } else {
+            return Err(InterpreterError::IncompatibleUnits(q.unit));
         }
 
-        self.stack.push(q);
+        self.push(q.into())?;
 
         Ok(())
     }
     /// Adds the top two quantities on the stack.
     pub fn op_add(&mut self) -> InterpreterResult<()> {
-        let rhs = self.stack.pop().ok_or(InterpreterError::StackUnderflow)?;
-        let lhs = self.stack.pop().ok_or(InterpreterError::StackUnderflow)?;
+        let (lhs, rhs) = self.pop_pair()?;
 
         self.warn_confusing_unit_conversions(&[&lhs, &rhs]);
 
-        self.stack
-            .push((lhs + rhs).map_err(InterpreterError::QuantityError)?);
+        self.push((lhs + rhs).map_err(InterpreterError::QuantityError)?.into())?;
 
         Ok(())
     }
     /// Subtracts the top two quantities on the stack.
     pub fn op_sub(&mut self) -> InterpreterResult<()> {
-        let rhs = self.stack.pop().ok_or(InterpreterError::StackUnderflow)?;
-        let lhs = self.stack.pop().ok_or(InterpreterError::StackUnderflow)?;
+        let (lhs, rhs) = self.pop_pair()?;
 
         self.warn_confusing_unit_conversions(&[&lhs, &rhs]);
 
-        self.stack
-            .push((lhs - rhs).map_err(InterpreterError::QuantityError)?);
+        self.push((lhs - rhs).map_err(InterpreterError::QuantityError)?.into())?;
 
         Ok(())
     }
     /// Multiplies the top two quantities on the stack.
     pub fn op_mul(&mut self) -> InterpreterResult<()> {
-        let rhs = self.stack.pop().ok_or(InterpreterError::StackUnderflow)?;
-        let lhs = self.stack.pop().ok_or(InterpreterError::StackUnderflow)?;
+        let (lhs, rhs) = self.pop_pair()?;
 
         self.warn_confusing_unit_conversions(&[&lhs, &rhs]);
 
-        self.stack.push(lhs * rhs);
+        self.push((lhs * rhs).map_err(InterpreterError::QuantityError)?.into())?;
 
         Ok(())
     }
     /// Divides the top two quantities on the stack.
     pub fn op_div(&mut self) -> InterpreterResult<()> {
-        let rhs = self.stack.pop().ok_or(InterpreterError::StackUnderflow)?;
-        let lhs = self.stack.pop().ok_or(InterpreterError::StackUnderflow)?;
+        let (lhs, rhs) = self.pop_pair()?;
 
         self.warn_confusing_unit_conversions(&[&lhs, &rhs]);
 
-        self.stack.push(lhs / rhs);
+        self.push((lhs / rhs).map_err(InterpreterError::QuantityError)?.into())?;
+
+        Ok(())
+    }
+    /// Raises the second quantity on the stack to the power of the top quantity, which must be unit-less.
+    ///
+    /// Fractional powers are allowed as long as every unit exponent remains an integer.
+    pub fn op_pow(&mut self) -> InterpreterResult<()> {
+        let (base, exponent) = self.pop_pair()?;
+
+        if !exponent.unit.is_unitless() {
+            return Err(InterpreterError::IncompatibleUnits(exponent.unit));
+        }
+        // Taking an n-th root takes time growing with n, so refuse large roots before computing them.
+        self.check_exponent(exponent.number.denom().to_u64().unwrap_or(u64::MAX))?;
+        let unit = base
+            .unit
+            .checked_pow(&exponent.number)
+            .map_err(InterpreterError::QuantityError)?;
+        self.check_exponent(unit.max_exponent().into())?;
+        // Raising an n-bit number to the power p takes about (n - 1) * p bits, so refuse before computing it.
+        let power = exponent
+            .number
+            .numer()
+            .magnitude()
+            .to_u64()
+            .unwrap_or(u64::MAX);
+        // The same holds for the scales of the derived units the result is displayed in.
+        for bits in std::iter::once(number_bits(&base.number))
+            .chain(base.use_derived_unit.iter().map(|d| number_bits(&d.scale)))
+        {
+            self.check_number_bits(bits.saturating_sub(1).saturating_mul(power))?;
+        }
+
+        self.push(
+            base.pow(&exponent.number)
+                .map_err(InterpreterError::QuantityError)?
+                .into(),
+        )?;
+
+        Ok(())
+    }
+    /// Negates the top of the stack.
+    pub fn op_neg(&mut self) -> InterpreterResult<()> {
+        let q = self.pop()?;
+
+        self.push((-q).into())?;
 
         Ok(())
     }
+    /// Compares the top two quantities on the stack, which must have the same dimension, and pushes 1 if
+    /// `cond` holds for the ordering of the second quantity relative to the top one, or 0 otherwise.
+    fn compare(&mut self, cond: impl Fn(Ordering) -> bool) -> InterpreterResult<()> {
+        let (lhs, rhs) = self.pop_pair()?;
+
+        let ordering = lhs
+            .checked_cmp(&rhs)
+            .map_err(InterpreterError::QuantityError)?;
+
+        self.op_number(BigRational::from_integer((cond(ordering) as i32).into()))
+    }
+    /// Pushes 1 if the second quantity on the stack is less than the top one, or 0 otherwise.
+    ///
+    /// For example, `5 (ml) 1 (l) <` pushes 1.
+    pub fn op_lt(&mut self) -> InterpreterResult<()> {
+        self.compare(Ordering::is_lt)
+    }
+    /// Pushes 1 if the second quantity on the stack is greater than the top one, or 0 otherwise.
+    pub fn op_gt(&mut self) -> InterpreterResult<()> {
+        self.compare(Ordering::is_gt)
+    }
+    /// Pushes 1 if the top two quantities on the stack are equal, or 0 otherwise.
+    pub fn op_eq(&mut self) -> InterpreterResult<()> {
+        self.compare(Ordering::is_eq)
+    }
+    /// Takes the square root of the top of the stack.
+    pub fn op_v(&mut self) -> InterpreterResult<()> {
+        let q = self.pop()?;
+
+        self.push(
+            q.pow(&BigRational::new(1.into(), 2.into()))
+                .map_err(InterpreterError::QuantityError)?
+                .into(),
+        )?;
+
+        Ok(())
+    }
+    /// Sends a value to the output. Strings are printed as they are, like dc.
+    fn print_value(&mut self, v: Value) {
+        match v {
+            Value::Quantity(q) => self.sink.emit(Output::Quantity(self.formatted(q))),
+            Value::String(s) => self.sink.emit(Output::Message(s)),
+        }
+    }
     /// Prints the top of the stack without altering it.
     pub fn op_p(&mut self) -> InterpreterResult<()> {
-        let q = self.stack.pop().ok_or(InterpreterError::StackUnderflow)?;
+        self.require(1)?;
 
-        (self.output)(Output::Quantity(q.clone()));
-
-        self.stack.push(q);
+        self.print_value(self.stack[self.stack.len() - 1].clone());
 
         Ok(())
     }
     /// Prints the top of the stack and pops it.
     pub fn op_n(&mut self) -> InterpreterResult<()> {
-        let q = self.stack.pop().ok_or(InterpreterError::StackUnderflow)?;
+        let v = self.pop_value()?;
 
-        (self.output)(Output::Quantity(q));
+        self.print_value(v);
 
         Ok(())
     }
     /// Prints the entire stack.
+    ///
+    /// A stack holding only quantities is sent as a list of quantities, otherwise it is printed as a message.
     pub fn op_f(&mut self) -> InterpreterResult<()> {
-        (self.output)(Output::QuantityList(self.stack.clone()));
+        let values = self
+            .stack
+            .iter()
+            .map(|v| match v {
+                Value::Quantity(q) => Value::Quantity(self.formatted(q.clone())),
+                v => v.clone(),
+            })
+            .collect::<Vec<_>>();
+
+        match values
+            .iter()
+            .cloned()
+            .map(Value::into_quantity)
+            .collect::<InterpreterResult<Vec<_>>>()
+        {
+            Ok(qs) => self.sink.emit(Output::QuantityList(qs)),
+            Err(_) => self.sink.emit(Output::Message(values.iter().join("\n"))),
+        }
 
         Ok(())
     }
     /// Duplicates the top of the stack.
     pub fn op_d(&mut self) -> InterpreterResult<()> {
-        let q = self.stack.pop().ok_or(InterpreterError::StackUnderflow)?;
+        let v = self.pop_value()?;
 
-        self.stack.push(q.clone());
-        self.stack.push(q);
+        self.push(v.clone())?;
+        self.push(v)?;
 
         Ok(())
     }
@@ -175,49 +295,68 @@ impl<'a> Interpreter<'a> {
     }
     /// Swaps the top two elements of the stack.
     pub fn op_r(&mut self) -> InterpreterResult<()> {
-        let a = self.stack.pop().ok_or(InterpreterError::StackUnderflow)?;
-        let b = self.stack.pop().ok_or(InterpreterError::StackUnderflow)?;
+        self.require(2)?;
 
-        self.stack.push(a);
-        self.stack.push(b);
+        let len = self.stack.len();
+        self.stack.swap(len - 1, len - 2);
 
         Ok(())
     }
-    /// Prints a summary of the unit system, including all base units, derived units, and their scale and offset.
-    pub fn op_upper_u(&mut self) -> InterpreterResult<()> {
-        let mut output = String::from("Base units:\n");
+    /// Pops a unit-less integer and sets it as the output precision.
+    ///
+    /// This is the number of digits after the decimal point in fixed notation, and the number of significant digits otherwise.
+    pub fn op_k(&mut self) -> InterpreterResult<()> {
+        let precision = self.pop_count()?;
+        // Formatting scales numbers by 10^precision, so refuse precisions that would hang printing.
+        self.check_precision(precision as u64)?;
 
-        for u in &self.unit_system.base_units() {
-            output.push_str(&format!("{}, ", u.symbol));
-        }
-        output.pop();
-        output.pop();
-        output.push_str("\n\nDerived units:\n");
-
-        for u in &self.unit_system.derived_units() {
-            output.push_str(&format!(
-                "{} = {} ({}) + {}\n",
-                u.symbol, u.scale, u.exponents, u.offset
-            ));
-        }
+        self.number_format.precision = u32::try_from(precision).map_err(|_| {
+            InterpreterError::InvalidArgument(format!("precision too large: {}", precision))
+        })?;
 
-        (self.output)(Output::Message(output));
+        Ok(())
+    }
+    /// Pushes the current output precision to the stack.
+    pub fn op_upper_k(&mut self) -> InterpreterResult<()> {
+        self.push(
+            Quantity::new(
+                BigRational::from_u32(self.number_format.precision).unwrap(),
+                UnitCombo::new(),
+            )
+            .into(),
+        )?;
+
+        Ok(())
+    }
+    /// Prints a summary of the unit system, including all base units, derived units, and their scale and offset, and all prefixes.
+    pub fn op_upper_u(&mut self) -> InterpreterResult<()> {
+        self.sink.emit(Output::UnitTable(UnitTable {
+            base_units: self.unit_system.base_units(),
+            derived_units: self.unit_system.derived_units(),
+            prefixes: self.unit_system.prefixes(),
+        }));
 
         Ok(())
     }
     /// Invokes the unit solver. See [here](https://github.com/eternal-flame-AD/unitdc-rs/wiki/The-Unit-Solver) for instructions.
     pub fn op_s(&mut self) -> InterpreterResult<()> {
-        let target = self.stack.pop().ok_or(InterpreterError::StackUnderflow)?;
+        let target = self.pop()?;
 
         // first figure out how many known quantities we have
-        let n_src_quantities = target.number_in_derived_unit().to_integer();
-        if n_src_quantities.to_usize().unwrap_or(0) > self.stack.len() {
-            return Err(InterpreterError::StackUnderflow);
+        let n_src_quantities = target
+            .number_in_derived_unit()
+            .map_err(InterpreterError::QuantityError)?
+            .to_integer();
+        let src_quantities = self.pop_n(n_src_quantities.to_usize().unwrap_or(0))?;
+        if src_quantities.is_empty() {
+            return Err(InterpreterError::NoSolution(
+                "no quantities to combine".to_string(),
+            ));
         }
-        let src_quantities = self
-            .stack
-            .split_off(self.stack.len() - n_src_quantities.to_usize().unwrap_or(0));
-        let dst_unit = target.unit.reduce();
+        let dst_unit = target
+            .unit
+            .reduce()
+            .map_err(InterpreterError::QuantityError)?;
         // Check that all units involved are present
         let mut units_involved = Vec::new();
         for q in &src_quantities {
@@ -232,6 +371,11 @@ impl<'a> Interpreter<'a> {
                 return Err(InterpreterError::IncompatibleUnits(dst_unit));
             }
         }
+        if units_involved.is_empty() {
+            return Err(InterpreterError::NoSolution(
+                "no units to solve for".to_string(),
+            ));
+        }
         let mut result_coefs = Vec::with_capacity(units_involved.len());
         for u in &units_involved {
             let mut coef = 0;
@@ -276,11 +420,34 @@ impl<'a> Interpreter<'a> {
             ));
         }
         let mut result = Quantity::new(BigRational::from_usize(1).unwrap(), dst_unit);
-        for (q, coef) in src_quantities.into_iter().zip(soln.unwrap()) {
-            result.number *= q.number.pow(coef);
+        let terms = src_quantities
+            .into_iter()
+            .zip(soln.unwrap())
+            .collect::<Vec<_>>();
+        // As in `^`, bound the size of the product before computing the powers.
+        let mut bits = 0u64;
+        for (q, coef) in &terms {
+            if q.number.is_zero() && *coef < 0 {
+                return Err(InterpreterError::QuantityError(
+                    QuantityError::DivisionByZero,
+                ));
+            }
+            bits = bits.saturating_add(
+                number_bits(&q.number)
+                    .saturating_sub(1)
+                    .saturating_mul(coef.unsigned_abs().into()),
+            );
+            self.check_number_bits(bits)?;
+        }
+        for (q, coef) in &terms {
+            result.number *= q.number.pow(*coef);
         }
         result.use_derived_unit = target.use_derived_unit;
-        self.stack.push(result);
+        self.sink.emit(Output::SolverReport(SolverReport {
+            terms,
+            result: self.formatted(result.clone()),
+        }));
+        self.push(result.into())?;
 
         Ok(())
     }
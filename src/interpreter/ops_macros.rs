@@ -12,11 +12,30 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use crate::quantity::units::{BaseUnit, DerivedUnit};
+use num_traits::Zero;
 
-use super::{Interpreter, InterpreterError, InterpreterResult};
+use crate::{
+    quantity::{
+        format::Notation,
+        units::{BaseUnit, DerivedUnit},
+        QuantityError,
+    },
+    tokenizer::{is_name_char, Tokenizer},
+};
 
-impl<'a> Interpreter<'a> {
+use super::{
+    limits::Limit,
+    output::{Definition, OutputSink},
+    value::Value,
+    Interpreter, InterpreterError, InterpreterResult, Output,
+};
+
+/// Names of the built-in macros, which cannot be redefined with `@def`.
+pub const BUILTIN_MACROS: &[&str] = &[
+    "base", "derived", "prefix", "noprefix", "format", "autounit", "undo", "redo", "def", "include",
+];
+
+impl<S: OutputSink> Interpreter<S> {
     /// Defines a new base unit.
     ///
     /// For example to define a unit "usd" (US Dollar), you would do:
@@ -24,13 +43,16 @@ impl<'a> Interpreter<'a> {
     pub fn op_macro_baseunit(&mut self, arg: &str) -> InterpreterResult<()> {
         let symbol = arg.trim();
 
-        if self.unit_system.lookup_unit(symbol).is_some() {
+        if self.unit_system.is_defined(symbol) {
             return Err(InterpreterError::AlreadyDefined(symbol.to_string()));
         }
 
-        self.unit_system.push_base_unit(BaseUnit {
+        let unit = BaseUnit {
             symbol: symbol.to_string(),
-        });
+        };
+        self.unit_system.push_base_unit(unit.clone());
+        self.sink
+            .emit(Output::UnitDefined(Definition::BaseUnit(unit)));
 
         Ok(())
     }
@@ -42,20 +64,270 @@ impl<'a> Interpreter<'a> {
     pub fn op_macro_derivedunit(&mut self, arg: &str) -> InterpreterResult<()> {
         let symbol = arg.trim();
 
-        let scale = self.stack.pop().ok_or(InterpreterError::StackUnderflow)?;
-        let offset = self.stack.pop().ok_or(InterpreterError::StackUnderflow)?;
+        let (offset, scale) = self.pop_pair()?;
 
-        if self.unit_system.lookup_unit(symbol).is_some() {
+        if self.unit_system.is_defined(symbol) {
             return Err(InterpreterError::AlreadyDefined(symbol.to_string()));
         }
+        if scale.number.is_zero() {
+            return Err(InterpreterError::QuantityError(
+                QuantityError::DivisionByZero,
+            ));
+        }
 
-        self.unit_system.push_derived_unit(DerivedUnit {
+        let unit = DerivedUnit {
             symbol: symbol.to_string(),
             scale: scale.number,
             offset: offset.number,
             exponents: offset.unit,
+        };
+        self.unit_system.push_derived_unit(unit.clone());
+        self.sink
+            .emit(Output::UnitDefined(Definition::DerivedUnit(unit)));
+
+        Ok(())
+    }
+    /// Defines a new unit prefix.
+    ///
+    /// This is done by popping a unit-less scale from the stack.
+    /// For example, to define the SI prefix "k" (kilo), you would do:
+    /// `1e3 @prefix(k)`
+    pub fn op_macro_prefix(&mut self, arg: &str) -> InterpreterResult<()> {
+        let symbol = arg.trim();
+
+        let scale = self.pop()?;
+
+        if !scale.unit.is_unitless() {
+            return Err(InterpreterError::IncompatibleUnits(scale.unit));
+        }
+        if self.unit_system.lookup_prefix(symbol).is_some() {
+            return Err(InterpreterError::AlreadyDefined(symbol.to_string()));
+        }
+        if scale.number.is_zero() {
+            return Err(InterpreterError::QuantityError(
+                QuantityError::DivisionByZero,
+            ));
+        }
+
+        self.unit_system.push_prefix(symbol, scale.number.clone());
+        self.sink.emit(Output::UnitDefined(Definition::Prefix {
+            symbol: symbol.to_string(),
+            scale: scale.number,
+        }));
+
+        Ok(())
+    }
+    /// Opts units out of taking a prefix, so that they are never read as part of a prefixed unit.
+    ///
+    /// For example, to stop "min" from resolving as milli-"in" when "min" itself is not defined:
+    /// `@noprefix(in)`
+    pub fn op_macro_noprefix(&mut self, arg: &str) -> InterpreterResult<()> {
+        for symbol in arg.split_whitespace() {
+            if !self.unit_system.is_defined(symbol) {
+                return Err(InterpreterError::UndefinedUnit(symbol.to_string()));
+            }
+            self.unit_system.push_no_prefix(symbol);
+        }
+
+        Ok(())
+    }
+    /// Sets the notation used to print quantities.
+    ///
+    /// One of `auto`, `fix` (fixed decimal places), `sci` (scientific), `eng` (engineering) or `frac` (exact fraction).
+    /// For example, to print 3 decimal places: `3 k @format(fix)`
+    pub fn op_macro_format(&mut self, arg: &str) -> InterpreterResult<()> {
+        self.number_format.notation = match arg.trim() {
+            "auto" => Notation::Auto,
+            "fix" => Notation::Fixed,
+            "sci" => Notation::Scientific,
+            "eng" => Notation::Engineering,
+            "frac" => Notation::Fraction,
+            other => {
+                return Err(InterpreterError::InvalidArgument(format!(
+                    "unknown notation: {}",
+                    other
+                )))
+            }
+        };
+
+        Ok(())
+    }
+    /// Turns automatic selection of the most readable derived unit for printed quantities `on` or `off`.
+    ///
+    /// For example, with `@autounit(on)`, `1e-9 (mol) p` prints `1 (nmol)`.
+    pub fn op_macro_autounit(&mut self, arg: &str) -> InterpreterResult<()> {
+        self.auto_unit = match arg.trim() {
+            "on" => true,
+            "off" => false,
+            other => {
+                return Err(InterpreterError::InvalidArgument(format!(
+                    "expected on or off, got {}",
+                    other
+                )))
+            }
+        };
+
+        Ok(())
+    }
+    /// Reverts the state to before the last input that changed it, optionally taking a number of steps.
+    ///
+    /// For example, to revert the last two inputs: `@undo(2)`
+    pub fn op_macro_undo(&mut self, arg: &str) -> InterpreterResult<()> {
+        for _ in 0..Self::parse_steps(arg)? {
+            if !self.undo() {
+                return Err(InterpreterError::NoHistory("undo".to_string()));
+            }
+        }
+
+        Ok(())
+    }
+    /// Re-applies inputs reverted by `@undo`, optionally taking a number of steps.
+    pub fn op_macro_redo(&mut self, arg: &str) -> InterpreterResult<()> {
+        for _ in 0..Self::parse_steps(arg)? {
+            if !self.redo() {
+                return Err(InterpreterError::NoHistory("redo".to_string()));
+            }
+        }
+
+        Ok(())
+    }
+    fn parse_steps(arg: &str) -> InterpreterResult<usize> {
+        match arg.trim() {
+            "" => Ok(1),
+            steps => steps.parse().map_err(|_| {
+                InterpreterError::InvalidArgument(format!(
+                    "expected a number of steps, got {}",
+                    steps
+                ))
+            }),
+        }
+    }
+    /// Runs the file at the given path. See [`Interpreter::include`].
+    ///
+    /// For example, to load definitions from `units/lab.rc` next to the current file:
+    /// `@include(units/lab.rc)`
+    pub fn op_macro_include(&mut self, arg: &str) -> InterpreterResult<()> {
+        self.include(arg.trim())
+    }
+    /// Runs a macro body as part of the input currently being processed.
+    ///
+    /// Errors are reported at the token that ran the macro, rather than once more for every level of nested macros.
+    pub(super) fn run_macro(&mut self, body: &str) -> InterpreterResult<()> {
+        self.run_nested(body).map_err(|e| match e {
+            InterpreterError::AtToken { error, .. } => *error,
+            e => e,
+        })
+    }
+    /// Runs a string as input, as part of the input currently being processed, keeping the token errors occur at.
+    pub(super) fn run_nested(&mut self, body: &str) -> InterpreterResult<()> {
+        if let Some(max) = self.limits.max_macro_depth {
+            if self.macro_depth >= max {
+                return Err(InterpreterError::LimitExceeded {
+                    limit: Limit::MacroDepth,
+                    max: max as u64,
+                });
+            }
+        }
+
+        let mut tokenizer =
+            Tokenizer::new(body).with_max_literal_exponent(self.limits.max_literal_exponent);
+        self.macro_depth += 1;
+        let result = self.process_tokens(&mut tokenizer);
+        self.macro_depth -= 1;
+
+        result
+    }
+    /// Pops a string and runs it. A quantity is pushed back unchanged, like dc.
+    ///
+    /// For example, `[2 *] >double 3 <double x` leaves 6 on the stack.
+    pub fn op_x(&mut self) -> InterpreterResult<()> {
+        match self.pop_value()? {
+            Value::String(body) => self.run_macro(&body),
+            v => {
+                self.push(v)?;
+                Ok(())
+            }
+        }
+    }
+    /// Pops a string and then a condition, and runs the string if the condition is not zero.
+    ///
+    /// For example, to warn when a concentration is above 1 M:
+    /// `<conc 1 (M) > [[warning: above 1 M] p D] ?`
+    pub fn op_cond(&mut self) -> InterpreterResult<()> {
+        let body = self.pop_value()?.into_string()?;
+        let cond = self.pop()?;
+
+        if cond.number.is_zero() {
+            return Ok(());
+        }
+
+        self.run_macro(&body)
+    }
+    /// Pops a string and then a count, and runs the string that many times.
+    ///
+    /// For example, to print a dilution table for 1, 2, 5, 10 and 20 uM of a 1 mM stock in 1 ml:
+    /// `20 10 5 2 1 5 [(uM) 1 (ml) * 1 (mM) / (ul) n] L`
+    pub fn op_upper_l(&mut self) -> InterpreterResult<()> {
+        let body = self.pop_value()?.into_string()?;
+        let count = self.pop_count()?;
+
+        if let Some(max) = self.limits.max_loop_iterations {
+            if count > max {
+                return Err(InterpreterError::LimitExceeded {
+                    limit: Limit::LoopIterations,
+                    max: max as u64,
+                });
+            }
+        }
+
+        for _ in 0..count {
+            self.run_macro(&body)?;
+        }
+
+        Ok(())
+    }
+    /// Defines a named macro, popping its body as a string from the stack.
+    ///
+    /// The macro is then invoked like a built-in macro with empty arguments. For example, to find the
+    /// concentration after diluting 100 ml of a 1 M solution to 2 l:
+    /// `[* r /] @def(dilute) 2 (l) 1 (M) 100 (ml) @dilute()`
+    pub fn op_macro_def(&mut self, arg: &str) -> InterpreterResult<()> {
+        let name = arg.trim();
+
+        let body = self.pop_value()?.into_string()?;
+
+        if name.is_empty() || !name.chars().all(|c| is_name_char(c) && !c.is_ascii_digit()) {
+            return Err(InterpreterError::InvalidArgument(format!(
+                "invalid macro name: {}",
+                name
+            )));
+        }
+        if BUILTIN_MACROS.contains(&name) {
+            return Err(InterpreterError::AlreadyDefined(name.to_string()));
+        }
+
+        self.macros.insert(name.to_string(), body);
+        self.sink.emit(Output::MacroDefined {
+            name: name.to_string(),
         });
 
         Ok(())
     }
+    /// Runs a macro defined with `@def`.
+    pub fn op_macro_user(&mut self, name: &str, arg: &str) -> InterpreterResult<()> {
+        let body = self
+            .macros
+            .get(name)
+            .ok_or_else(|| InterpreterError::UndefinedMacro(name.to_string()))?
+            .clone();
+
+        if !arg.trim().is_empty() {
+            return Err(InterpreterError::InvalidArgument(format!(
+                "@{} takes no arguments, got {}",
+                name, arg
+            )));
+        }
+
+        self.run_macro(&body)
+    }
 }
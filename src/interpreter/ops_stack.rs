@@ -0,0 +1,88 @@
+// Copyright 2024 eternal-flame-AD <yume@yumechi.jp>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use num_rational::BigRational;
+use num_traits::FromPrimitive;
+
+use crate::quantity::{units::UnitCombo, Quantity};
+
+use super::{output::OutputSink, Interpreter, InterpreterResult};
+
+impl<S: OutputSink> Interpreter<S> {
+    /// Drops the top of the stack.
+    pub fn op_upper_d(&mut self) -> InterpreterResult<()> {
+        self.pop_value()?;
+
+        Ok(())
+    }
+    /// Rotates the top three elements of the stack, bringing the third element to the top.
+    ///
+    /// `a b c` becomes `b c a`, like Forth's `ROT`.
+    pub fn op_t(&mut self) -> InterpreterResult<()> {
+        self.require(3)?;
+
+        let v = self.stack.remove(self.stack.len() - 3);
+        self.push(v)?;
+
+        Ok(())
+    }
+    /// Pops `n`, then copies the `n`-th element of the stack to the top, counting from 0 at the top.
+    ///
+    /// `0 y` is the same as `d`, like Forth's `PICK`.
+    pub fn op_y(&mut self) -> InterpreterResult<()> {
+        let n = self.pop_count()?;
+        self.require(n.saturating_add(1))?;
+
+        let v = self.stack[self.stack.len() - 1 - n].clone();
+        self.push(v)?;
+
+        Ok(())
+    }
+    /// Pops `n`, then moves the `n`-th element of the stack to the top, counting from 0 at the top.
+    ///
+    /// `1 R` is the same as `r`, and `2 R` is the same as `t`, like Forth's `ROLL`.
+    pub fn op_upper_r(&mut self) -> InterpreterResult<()> {
+        let n = self.pop_count()?;
+        self.require(n.saturating_add(1))?;
+
+        let v = self.stack.remove(self.stack.len() - 1 - n);
+        self.push(v)?;
+
+        Ok(())
+    }
+    /// Pushes the depth of the stack as a unit-less quantity.
+    pub fn op_z(&mut self) -> InterpreterResult<()> {
+        self.push(
+            Quantity::new(
+                BigRational::from_usize(self.stack.len()).unwrap(),
+                UnitCombo::new(),
+            )
+            .into(),
+        )?;
+
+        Ok(())
+    }
+    /// Pops `n`, then duplicates the top `n` elements of the stack, keeping their order.
+    ///
+    /// `2 Y` turns `a b` into `a b a b`, like Forth's `2DUP`.
+    pub fn op_upper_y(&mut self) -> InterpreterResult<()> {
+        let n = self.pop_count()?;
+        self.require(n)?;
+        self.check_stack_depth(self.stack.len() + n)?;
+
+        self.stack.extend_from_within(self.stack.len() - n..);
+
+        Ok(())
+    }
+}
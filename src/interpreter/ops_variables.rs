@@ -12,30 +12,34 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use super::{Interpreter, InterpreterError, InterpreterResult};
+use super::{output::OutputSink, Interpreter, InterpreterError, InterpreterResult, Output};
 
-impl<'a> Interpreter<'a> {
-    /// Pops a quantity from the stack and stores it in a variable.
+impl<S: OutputSink> Interpreter<S> {
+    /// Pops a value from the stack and stores it in a variable.
     pub fn op_store(&mut self, arg: &str) -> InterpreterResult<()> {
         let symbol = arg.trim();
 
-        let q = self.stack.pop().ok_or(InterpreterError::StackUnderflow)?;
+        let v = self.pop_value()?;
 
-        self.variables.insert(symbol.to_string(), q);
+        self.sink.emit(Output::VariableAssigned {
+            name: symbol.to_string(),
+            value: v.clone(),
+        });
+        self.variables.insert(symbol.to_string(), v);
 
         Ok(())
     }
-    /// Pushes a quantity from a variable onto the stack.
+    /// Pushes a value from a variable onto the stack.
     pub fn op_recall(&mut self, arg: &str) -> InterpreterResult<()> {
         let symbol = arg.trim();
 
-        let q = self
+        let v = self
             .variables
             .get(symbol)
             .ok_or(InterpreterError::UndefinedVariable(symbol.to_string()))?
             .clone();
 
-        self.stack.push(q);
+        self.push(v)?;
 
         Ok(())
     }
@@ -0,0 +1,226 @@
+// Copyright 2024 eternal-flame-AD <yume@yumechi.jp>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{fmt::Display, io::Write};
+
+use num_rational::BigRational;
+use serde::Serialize;
+
+use crate::quantity::{
+    units::{BaseUnit, DerivedUnit},
+    Quantity,
+};
+
+use super::Output;
+
+/// What a [`Warning`] is about.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum WarningKind {
+    /// Quantities in different derived units with an offset over the same base unit were combined, so the
+    /// offsets may not mean what was intended, as in adding two temperatures in degrees Celsius.
+    OffsetUnitsCombined { base_unit: BaseUnit },
+}
+
+/// Something that did not fail, but may not give the expected result.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Warning {
+    pub kind: WarningKind,
+    /// The derived units involved.
+    pub units: Vec<String>,
+}
+
+impl Display for Warning {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.kind {
+            WarningKind::OffsetUnitsCombined { base_unit } => write!(
+                f,
+                "Warning: {} it is used in multiple quantities with an offset. This may lead to unexpected results. Affected derived units: [{}]",
+                base_unit,
+                self.units.join(", ")
+            ),
+        }
+    }
+}
+
+/// A summary of the unit system, as printed by `U`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct UnitTable {
+    pub base_units: Vec<BaseUnit>,
+    pub derived_units: Vec<DerivedUnit>,
+    pub prefixes: Vec<(String, BigRational)>,
+}
+
+impl Display for UnitTable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "Base units:")?;
+        let base_units = self
+            .base_units
+            .iter()
+            .map(|u| u.symbol.as_str())
+            .collect::<Vec<_>>();
+        writeln!(f, "{}", base_units.join(", "))?;
+        writeln!(f, "\nDerived units:")?;
+        for u in &self.derived_units {
+            writeln!(
+                f,
+                "{} = {} ({}) + {}",
+                u.symbol, u.scale, u.exponents, u.offset
+            )?;
+        }
+        writeln!(f, "\nPrefixes:")?;
+        for (p, scale) in &self.prefixes {
+            writeln!(f, "{} = {}", p, scale)?;
+        }
+        Ok(())
+    }
+}
+
+/// How the unit solver `s` combined the given quantities into the result.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct SolverReport {
+    /// Each source quantity with the power it was raised to.
+    pub terms: Vec<(Quantity, i32)>,
+    pub result: Quantity,
+}
+
+impl Display for SolverReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let terms = self
+            .terms
+            .iter()
+            .map(|(q, exponent)| format!("{}^{}", q, exponent))
+            .collect::<Vec<_>>();
+        write!(f, "{} = {}", terms.join(" * "), self.result)
+    }
+}
+
+/// A unit or prefix defined with `@base`, `@derived` or `@prefix`.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Definition {
+    BaseUnit(BaseUnit),
+    DerivedUnit(DerivedUnit),
+    Prefix { symbol: String, scale: BigRational },
+}
+
+/// Where the interpreter sends its [`Output`].
+pub trait OutputSink {
+    fn emit(&mut self, output: Output);
+}
+
+/// Collects every output, in order.
+impl OutputSink for Vec<Output> {
+    fn emit(&mut self, output: Output) {
+        self.push(output);
+    }
+}
+
+/// Sends each output to a closure.
+///
+/// For example, to print quantities as they come: `FnSink(|output| println!("{:?}", output))`
+pub struct FnSink<F: FnMut(Output)>(pub F);
+
+impl<F: FnMut(Output)> OutputSink for FnSink<F> {
+    fn emit(&mut self, output: Output) {
+        (self.0)(output);
+    }
+}
+
+/// Writes outputs as text, one line per quantity or message.
+///
+/// Lists of quantities are written from the top of the stack down, each line prefixed with its position.
+/// Only what is printed is written, not reports of variables and units being defined or of the unit solver.
+/// Write errors do not stop the interpreter, the first one is kept for [`TextSink::take_error`] instead.
+pub struct TextSink<W: Write> {
+    writer: W,
+    error: Option<std::io::Error>,
+}
+
+impl<W: Write> TextSink<W> {
+    pub fn new(writer: W) -> Self {
+        TextSink {
+            writer,
+            error: None,
+        }
+    }
+    /// Takes the first write error since the last call, if any.
+    pub fn take_error(&mut self) -> Option<std::io::Error> {
+        self.error.take()
+    }
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+    fn write(&mut self, output: Output) -> std::io::Result<()> {
+        match output {
+            Output::Quantity(q) => writeln!(self.writer, "{}", q),
+            Output::QuantityList(qs) => qs
+                .iter()
+                .rev()
+                .enumerate()
+                .try_for_each(|(i, q)| writeln!(self.writer, "[{}]: {}", i, q)),
+            Output::Message(m) => writeln!(self.writer, "{}", m),
+            Output::Warning(w) => writeln!(self.writer, "{}", w),
+            Output::UnitTable(t) => write!(self.writer, "{}", t),
+            Output::SolverReport(_)
+            | Output::VariableAssigned { .. }
+            | Output::UnitDefined(_)
+            | Output::MacroDefined { .. } => Ok(()),
+        }
+    }
+}
+
+impl<W: Write> OutputSink for TextSink<W> {
+    fn emit(&mut self, output: Output) {
+        if let Err(e) = self.write(output) {
+            self.error.get_or_insert(e);
+        }
+    }
+}
+
+/// Writes each output as a line of JSON, such as `{"type":"message","value":"..."}`.
+///
+/// Write errors are kept like in [`TextSink`].
+pub struct JsonLinesSink<W: Write> {
+    writer: W,
+    error: Option<std::io::Error>,
+}
+
+impl<W: Write> JsonLinesSink<W> {
+    pub fn new(writer: W) -> Self {
+        JsonLinesSink {
+            writer,
+            error: None,
+        }
+    }
+    /// Takes the first write error since the last call, if any.
+    pub fn take_error(&mut self) -> Option<std::io::Error> {
+        self.error.take()
+    }
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+    fn write(&mut self, output: Output) -> std::io::Result<()> {
+        serde_json::to_writer(&mut self.writer, &output)?;
+        writeln!(self.writer)
+    }
+}
+
+impl<W: Write> OutputSink for JsonLinesSink<W> {
+    fn emit(&mut self, output: Output) {
+        if let Err(e) = self.write(output) {
+            self.error.get_or_insert(e);
+        }
+    }
+}
@@ -0,0 +1,114 @@
+// Copyright 2024 eternal-flame-AD <yume@yumechi.jp>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use serde::{Deserialize, Serialize};
+
+use crate::quantity::format::NumberFormat;
+
+use super::{
+    history::Snapshot, limits::number_bits, output::OutputSink, value::Value, Interpreter,
+    InterpreterError, InterpreterResult,
+};
+
+/// The version of the session format written by [`Interpreter::export_session`].
+pub const SESSION_VERSION: u32 = 1;
+
+/// Everything needed to pick up where a previous interpreter left off.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Session {
+    pub version: u32,
+    #[serde(flatten)]
+    pub state: Snapshot,
+    pub number_format: NumberFormat,
+    pub auto_unit: bool,
+}
+
+impl<S: OutputSink> Interpreter<S> {
+    /// Exports the stack, variables, macros, unit system and output settings as JSON.
+    ///
+    /// Undo history, limits and the output sink are not part of the session.
+    pub fn export_session(&self) -> String {
+        let session = Session {
+            version: SESSION_VERSION,
+            state: self.snapshot(),
+            number_format: self.number_format,
+            auto_unit: self.auto_unit,
+        };
+        // All maps in a session have string keys, so serializing to JSON cannot fail.
+        serde_json::to_string(&session).expect("session should serialize")
+    }
+    /// Replaces the current state with a session from [`Interpreter::export_session`].
+    ///
+    /// The import can be undone like an input. It fails without changing anything if the JSON is not a
+    /// session of a supported version, if a number in it has a zero denominator, or if its numbers, unit scales,
+    /// stack depth or precision exceed the current limits.
+    pub fn import_session(&mut self, json: &str) -> InterpreterResult<()> {
+        #[derive(Deserialize)]
+        struct Version {
+            version: u32,
+        }
+
+        let invalid = |e: serde_json::Error| InterpreterError::InvalidSession(e.to_string());
+        let Version { version } = serde_json::from_str(json).map_err(invalid)?;
+        if version != SESSION_VERSION {
+            return Err(InterpreterError::InvalidSession(format!(
+                "unsupported version {}, expected {}",
+                version, SESSION_VERSION
+            )));
+        }
+        let mut session: Session = serde_json::from_str(json).map_err(invalid)?;
+
+        // Hold the session to the same limits as the inputs that could have built it.
+        self.check_precision(session.number_format.precision.into())?;
+        self.check_stack_depth(session.state.stack.len())?;
+        self.check_value_limits(
+            session
+                .state
+                .stack
+                .iter()
+                .chain(session.state.variables.values()),
+        )?;
+        let units = &session.state.unit_system;
+        for unit in units.derived_units() {
+            self.check_number_bits(number_bits(&unit.scale).max(number_bits(&unit.offset)))?;
+        }
+        for (_, scale) in units.prefixes() {
+            self.check_number_bits(number_bits(&scale))?;
+        }
+
+        // Numbers are deserialized as written, but everything else expects them in lowest terms.
+        let state = &mut session.state;
+        state
+            .stack
+            .iter_mut()
+            .chain(state.variables.values_mut())
+            .try_for_each(|v| match v {
+                Value::Quantity(q) => q.normalize(),
+                Value::String(_) => Ok(()),
+            })
+            .and_then(|()| state.unit_system.normalize())
+            .map_err(|_| {
+                InterpreterError::InvalidSession("number with a zero denominator".to_string())
+            })?;
+
+        let before = self.snapshot();
+        self.restore(session.state);
+        self.number_format = session.number_format;
+        self.auto_unit = session.auto_unit;
+        self.history.moved = false;
+        self.record_history(before);
+
+        Ok(())
+    }
+}
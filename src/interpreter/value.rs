@@ -0,0 +1,68 @@
+// Copyright 2024 eternal-flame-AD <yume@yumechi.jp>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::fmt::Display;
+
+use serde::{Deserialize, Serialize};
+
+use crate::quantity::Quantity;
+
+use super::{InterpreterError, InterpreterResult};
+
+/// An item on the stack or in a variable.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", content = "value", rename_all = "snake_case")]
+pub enum Value {
+    Quantity(Quantity),
+    /// A string, such as the body of a macro written as `[...]`.
+    String(String),
+}
+
+impl Value {
+    /// Unwraps a quantity, failing with a type mismatch for anything else.
+    pub fn into_quantity(self) -> InterpreterResult<Quantity> {
+        match self {
+            Value::Quantity(q) => Ok(q),
+            v => Err(InterpreterError::TypeMismatch {
+                expected: "quantity".to_string(),
+                found: v.to_string(),
+            }),
+        }
+    }
+    /// Unwraps a string, failing with a type mismatch for anything else.
+    pub fn into_string(self) -> InterpreterResult<String> {
+        match self {
+            Value::String(s) => Ok(s),
+            v => Err(InterpreterError::TypeMismatch {
+                expected: "string".to_string(),
+                found: v.to_string(),
+            }),
+        }
+    }
+}
+
+impl From<Quantity> for Value {
+    fn from(q: Quantity) -> Self {
+        Value::Quantity(q)
+    }
+}
+
+impl Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Value::Quantity(q) => write!(f, "{}", q),
+            Value::String(s) => write!(f, "[{}]", s),
+        }
+    }
+}
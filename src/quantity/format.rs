@@ -0,0 +1,228 @@
+// Copyright 2024 eternal-flame-AD <yume@yumechi.jp>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use num_bigint::BigInt;
+use num_rational::BigRational;
+use num_traits::{One, Signed, Zero};
+use serde::{Deserialize, Serialize};
+
+/// How a number is laid out when formatted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Notation {
+    /// Rounds to `precision` significant digits, switching to scientific notation for very large or small numbers.
+    Auto,
+    /// Rounds to `precision` digits after the decimal point.
+    Fixed,
+    /// Rounds to `precision` significant digits, always with one digit before the decimal point and an exponent.
+    Scientific,
+    /// Like [`Notation::Scientific`], but the exponent is always a multiple of 3.
+    Engineering,
+    /// Prints the exact fraction `p/q`, ignoring `precision`.
+    Fraction,
+}
+
+/// Options for formatting a [`BigRational`] without going through a lossy float.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NumberFormat {
+    pub notation: Notation,
+    pub precision: u32,
+}
+
+impl Default for NumberFormat {
+    fn default() -> Self {
+        NumberFormat {
+            notation: Notation::Auto,
+            precision: 15,
+        }
+    }
+}
+
+/// Computes 10^exp as a rational.
+fn pow10(exp: i64) -> BigRational {
+    let p = BigInt::from(10).pow(exp.unsigned_abs() as u32);
+    if exp >= 0 {
+        BigRational::from_integer(p)
+    } else {
+        BigRational::new(BigInt::one(), p)
+    }
+}
+
+/// Computes floor(log10(x)) for a positive rational.
+fn floor_log10(x: &BigRational) -> i64 {
+    let mut exp = x.numer().to_string().len() as i64 - x.denom().to_string().len() as i64;
+    if *x < pow10(exp) {
+        exp -= 1;
+    }
+    exp
+}
+
+/// Rounds |x| to `precision` significant digits.
+///
+/// Returns the digits as an integer, and the decimal exponent of the leading digit.
+fn round_significant(x: &BigRational, precision: i64) -> (BigRational, i64) {
+    let mut exp = floor_log10(&x.abs());
+    let mut rounded = (x.abs() * pow10(precision - 1 - exp)).round();
+    if rounded >= BigRational::from_integer(BigInt::from(10).pow(precision as u32)) {
+        // rounding carried into a new digit, e.g. 9.99 -> 10.0
+        exp += 1;
+        rounded = (rounded / BigInt::from(10)).round();
+    }
+    (rounded, exp)
+}
+
+/// Places a decimal point `frac_digits` digits from the right of `digits`, padding with zeros as needed.
+fn insert_point(digits: &str, frac_digits: usize) -> String {
+    if frac_digits == 0 {
+        return digits.to_string();
+    }
+    let digits = format!("{:0>width$}", digits, width = frac_digits + 1);
+    let (int, frac) = digits.split_at(digits.len() - frac_digits);
+    format!("{}.{}", int, frac)
+}
+
+fn trim_fraction(s: String) -> String {
+    if s.contains('.') {
+        s.trim_end_matches('0').trim_end_matches('.').to_string()
+    } else {
+        s
+    }
+}
+
+impl NumberFormat {
+    /// Formats a rational number according to these options.
+    pub fn format(&self, number: &BigRational) -> String {
+        match self.notation {
+            Notation::Fraction => {
+                if number.is_integer() {
+                    number.numer().to_string()
+                } else {
+                    format!("{}/{}", number.numer(), number.denom())
+                }
+            }
+            Notation::Fixed => {
+                let rounded = (number.abs() * pow10(self.precision as i64)).round();
+                let digits = insert_point(&rounded.numer().to_string(), self.precision as usize);
+                Self::with_sign(number, &rounded, digits)
+            }
+            Notation::Auto | Notation::Scientific | Notation::Engineering => {
+                if number.is_zero() {
+                    return "0".to_string();
+                }
+                let precision = self.precision.max(1) as i64;
+                let (rounded, exp) = round_significant(number, precision);
+                let digits = rounded.numer().to_string();
+                let body = match self.notation {
+                    Notation::Auto if (-7..21).contains(&exp) => {
+                        if exp >= precision - 1 {
+                            format!("{}{}", digits, "0".repeat((exp - precision + 1) as usize))
+                        } else {
+                            trim_fraction(insert_point(&digits, (precision - 1 - exp) as usize))
+                        }
+                    }
+                    Notation::Engineering => {
+                        let eng_exp = exp - exp.rem_euclid(3);
+                        let int_digits = (exp - eng_exp + 1) as usize;
+                        let digits = format!("{:0<width$}", digits, width = int_digits);
+                        format!(
+                            "{}e{}",
+                            trim_fraction(insert_point(&digits, digits.len() - int_digits)),
+                            eng_exp
+                        )
+                    }
+                    _ => format!(
+                        "{}e{}",
+                        trim_fraction(insert_point(&digits, (precision - 1) as usize)),
+                        exp
+                    ),
+                };
+                Self::with_sign(number, &rounded, body)
+            }
+        }
+    }
+    fn with_sign(number: &BigRational, rounded: &BigRational, body: String) -> String {
+        if number.is_negative() && !rounded.is_zero() {
+            format!("-{}", body)
+        } else {
+            body
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ratio(n: i64, d: i64) -> BigRational {
+        BigRational::new(n.into(), d.into())
+    }
+
+    #[test]
+    fn test_format() {
+        let auto = NumberFormat::default();
+        assert_eq!(auto.format(&ratio(2000, 1)), "2000");
+        assert_eq!(auto.format(&ratio(-1, 10)), "-0.1");
+        assert_eq!(auto.format(&ratio(1, 3)), "0.333333333333333");
+        assert_eq!(auto.format(&ratio(2, 3)), "0.666666666666667");
+        assert_eq!(auto.format(&ratio(1, 1_000_000_000)), "1e-9");
+        assert_eq!(
+            auto.format(&BigRational::from_integer(BigInt::from(10).pow(400))),
+            "1e400"
+        );
+
+        let sig3 = NumberFormat {
+            notation: Notation::Auto,
+            precision: 3,
+        };
+        assert_eq!(sig3.format(&ratio(9999, 1000)), "10");
+        assert_eq!(sig3.format(&ratio(123456, 1)), "123000");
+
+        let fixed = NumberFormat {
+            notation: Notation::Fixed,
+            precision: 2,
+        };
+        assert_eq!(fixed.format(&ratio(1, 3)), "0.33");
+        assert_eq!(fixed.format(&ratio(-1, 1000)), "0.00");
+        assert_eq!(fixed.format(&ratio(-5, 2)), "-2.50");
+
+        let sci = NumberFormat {
+            notation: Notation::Scientific,
+            precision: 4,
+        };
+        assert_eq!(sci.format(&ratio(123456, 1)), "1.235e5");
+        assert_eq!(sci.format(&ratio(-1, 800)), "-1.25e-3");
+
+        let eng = NumberFormat {
+            notation: Notation::Engineering,
+            precision: 4,
+        };
+        assert_eq!(eng.format(&ratio(123456, 1)), "123.5e3");
+        assert_eq!(eng.format(&ratio(1, 800)), "1.25e-3");
+        assert_eq!(eng.format(&ratio(1, 80)), "12.5e-3");
+        assert_eq!(
+            NumberFormat {
+                notation: Notation::Engineering,
+                precision: 1,
+            }
+            .format(&ratio(12345, 1)),
+            "10e3"
+        );
+
+        let fraction = NumberFormat {
+            notation: Notation::Fraction,
+            precision: 0,
+        };
+        assert_eq!(fraction.format(&ratio(-2, 6)), "-1/3");
+        assert_eq!(fraction.format(&ratio(4, 2)), "2");
+    }
+}
@@ -13,47 +13,116 @@
 // limitations under the License.
 
 use std::{
+    cmp::Ordering,
     fmt::Display,
-    ops::{Add, Div, Mul, Sub},
+    ops::{Add, Div, Mul, Neg, Sub},
 };
 
 use itertools::Itertools;
+use num_bigint::BigInt;
 use num_rational::BigRational;
-use num_traits::ToPrimitive;
+use num_traits::{CheckedDiv, Signed, ToPrimitive, Zero};
 use serde::{ser::SerializeStruct, Deserialize, Serialize, Serializer};
 use thiserror::Error;
 
+pub mod format;
+pub mod unit_expr;
 pub mod units;
 
+use format::NumberFormat;
 use units::{DerivedUnit, UnitCombo};
 
-/// A quantity of a combination of a number, the unit combination, and a list of derived units to use when formatting the quantity.
+/// A quantity of a combination of a number, the unit combination, and a list of derived units and a number format to use when formatting the quantity.
 #[derive(Debug, Clone, PartialEq, Deserialize)]
 pub struct Quantity {
     pub number: BigRational,
     pub unit: UnitCombo,
 
     pub use_derived_unit: Vec<DerivedUnit>,
+    #[serde(default)]
+    pub use_format: Option<NumberFormat>,
 }
 
 impl Serialize for Quantity {
     fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
-        let mut s = serializer.serialize_struct("Quantity", 5)?;
+        let mut s = serializer.serialize_struct("Quantity", 6)?;
         s.serialize_field("_str", &self.to_string())?;
         s.serialize_field("number_float", &self.number.to_f64())?;
         s.serialize_field("number", &self.number)?;
         s.serialize_field("unit", &self.unit)?;
         s.serialize_field("use_derived_unit", &self.use_derived_unit)?;
+        s.serialize_field("use_format", &self.use_format)?;
         s.end()
     }
 }
 
-#[derive(Error, Debug)]
+#[derive(Error, Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "reason", content = "value", rename_all = "snake_case")]
 pub enum QuantityError {
     #[error("Incompatible units")]
     IncompatibleUnits,
     #[error("Unknown unit")]
     UnknownUnit,
+    #[error("Raising to the power of {0} does not give integer unit exponents")]
+    NonIntegerExponent(BigRational),
+    #[error("Exponent out of range: {0}")]
+    ExponentOutOfRange(BigRational),
+    #[error("Unit exponent out of range")]
+    UnitExponentOverflow,
+    #[error("Even root of a negative number")]
+    NegativeRoot,
+    #[error("Division by zero")]
+    DivisionByZero,
+}
+
+/// The longest symbol a product or quotient of derived units may have to be kept on the result.
+///
+/// Without a bound, repeatedly squaring a quantity would double the length of its derived unit symbols every time.
+pub const MAX_DERIVED_SYMBOL_LEN: usize = 64;
+
+/// Number of bits of precision kept when a root cannot be computed exactly.
+pub const ROOT_PRECISION_BITS: u64 = 256;
+
+/// Brings a rational into lowest terms with a positive denominator, failing if the denominator is zero.
+///
+/// Arithmetic always gives normalized rationals, but deserialized ones are kept as written.
+pub fn normalize(x: &mut BigRational) -> Result<(), QuantityError> {
+    if x.denom().is_zero() {
+        return Err(QuantityError::DivisionByZero);
+    }
+    *x = BigRational::new(x.numer().clone(), x.denom().clone());
+    Ok(())
+}
+
+/// Computes the `n`-th root of a rational number, if the result is rational.
+pub fn exact_root(x: &BigRational, n: u32) -> Option<BigRational> {
+    if n == 0 || (x.is_negative() && n.is_multiple_of(2)) {
+        return None;
+    }
+    let root_of = |i: &BigInt| {
+        let r = i.abs().nth_root(n);
+        (r.pow(n) == i.abs()).then_some(r)
+    };
+    let root = BigRational::new(root_of(x.numer())?, root_of(x.denom())?);
+    Some(if x.is_negative() { -root } else { root })
+}
+
+/// Computes the `n`-th root of a rational number, approximating it if the result is irrational.
+///
+/// Returns `None` for even roots of negative numbers.
+pub fn approx_root(x: &BigRational, n: u32) -> Option<BigRational> {
+    if let Some(root) = exact_root(x, n) {
+        return Some(root);
+    }
+    if n == 0 || (x.is_negative() && n.is_multiple_of(2)) {
+        return None;
+    }
+    // root(a / b) = root(a * b^(n - 1)) / b, scaled by 2^k to keep enough bits after truncation
+    let denom = x.denom();
+    let radicand = x.numer().abs() * denom.pow(n - 1);
+    let k = ROOT_PRECISION_BITS.saturating_sub(radicand.bits() / n as u64);
+    let root = BigRational::new((radicand << (k * n as u64)).nth_root(n), denom << k);
+    Some(if x.is_negative() { -root } else { root })
 }
 
 impl Quantity {
@@ -63,40 +132,101 @@ impl Quantity {
             number,
             unit,
             use_derived_unit: Vec::new(),
+            use_format: None,
         }
     }
     /// Computes the "user-facing" number of the quantity, considering the offset and scale of matching derived units.
-    pub fn number_in_derived_unit(&self) -> BigRational {
-        let mut number = self.number.clone();
+    ///
+    /// Fails if the matching derived unit has a zero scale.
+    pub fn number_in_derived_unit(&self) -> Result<BigRational, QuantityError> {
+        let number = self.number.clone();
 
         for d in &self.use_derived_unit {
             if d.exponents == self.unit {
-                number -= d.offset.clone();
-                number /= d.scale.clone();
-                return number;
+                return (number - d.offset.clone())
+                    .checked_div(&d.scale)
+                    .ok_or(QuantityError::DivisionByZero);
             }
         }
 
-        number
+        Ok(number)
     }
-}
-
-impl Display for Quantity {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let mut number = self.number.clone();
+    /// Formats the quantity with the given number format, regardless of [`Quantity::use_format`].
+    pub fn to_string_with(&self, format: NumberFormat) -> String {
+        let mut s = String::new();
+        self.write_with(&mut s, format)
+            .expect("writing to a String should not fail");
+        s
+    }
+    fn write_with<W: std::fmt::Write>(&self, w: &mut W, format: NumberFormat) -> std::fmt::Result {
+        // A derived unit with a zero scale cannot be displayed, so fall back to base units.
+        let Ok(number) = self.number_in_derived_unit() else {
+            return write!(w, "{} ({})", format.format(&self.number), self.unit);
+        };
+        let number = format.format(&number);
 
         for d in &self.use_derived_unit {
             if d.exponents == self.unit {
-                number -= d.offset.clone();
-                number /= d.scale.clone();
-                write!(f, "{} ({})", number.to_f64().unwrap_or(f64::NAN), d)?;
-                return Ok(());
+                return write!(w, "{} ({})", number, d);
             }
         }
 
-        write!(f, "{} ({})", number.to_f64().unwrap_or(f64::NAN), self.unit)?;
+        write!(w, "{} ({})", number, self.unit)
+    }
+    /// Raises the quantity to a rational power.
+    ///
+    /// The result is exact whenever it is rational, otherwise the root is approximated.
+    /// Every unit exponent must remain an integer after being scaled by the power.
+    pub fn pow(&self, exponent: &BigRational) -> Result<Self, QuantityError> {
+        let unit = self.unit.checked_pow(exponent)?;
+        if self.number.is_zero() && exponent.is_negative() {
+            return Err(QuantityError::DivisionByZero);
+        }
+        let numer = exponent
+            .numer()
+            .to_i32()
+            .ok_or_else(|| QuantityError::ExponentOutOfRange(exponent.clone()))?;
+        let denom = exponent
+            .denom()
+            .to_u32()
+            .ok_or_else(|| QuantityError::ExponentOutOfRange(exponent.clone()))?;
+        let number =
+            approx_root(&self.number.pow(numer), denom).ok_or(QuantityError::NegativeRoot)?;
 
-        Ok(())
+        Ok(Quantity {
+            number,
+            unit,
+            use_derived_unit: self
+                .use_derived_unit
+                .iter()
+                .filter_map(|d| d.checked_pow(exponent))
+                .collect(),
+            use_format: None,
+        })
+    }
+    /// Normalizes the number and the scales and offsets of the derived units, see [`normalize`].
+    pub fn normalize(&mut self) -> Result<(), QuantityError> {
+        normalize(&mut self.number)?;
+        self.use_derived_unit
+            .iter_mut()
+            .try_for_each(DerivedUnit::normalize)
+    }
+    /// Compares two quantities of the same dimension by their value in base units.
+    ///
+    /// This is not a [`PartialOrd`] implementation, as quantities that compare equal here may still differ in
+    /// the derived units they are displayed in.
+    pub fn checked_cmp(&self, other: &Self) -> Result<Ordering, QuantityError> {
+        if self.unit != other.unit {
+            return Err(QuantityError::IncompatibleUnits);
+        }
+
+        Ok(self.number.cmp(&other.number))
+    }
+}
+
+impl Display for Quantity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.write_with(f, self.use_format.unwrap_or_default())
     }
 }
 
@@ -121,6 +251,7 @@ impl Add for Quantity {
             number,
             unit,
             use_derived_unit,
+            use_format: None,
         })
     }
 }
@@ -146,20 +277,30 @@ impl Sub for Quantity {
             number,
             unit,
             use_derived_unit,
+            use_format: None,
         })
     }
 }
 
+/// Whether the product or quotient of two derived units has a short enough symbol to be kept.
+fn product_symbol_fits(lhs: &DerivedUnit, rhs: &DerivedUnit) -> bool {
+    lhs.symbol.len() + rhs.symbol.len() < MAX_DERIVED_SYMBOL_LEN
+}
+
 impl Mul for Quantity {
-    type Output = Self;
+    type Output = Result<Self, QuantityError>;
 
     fn mul(self, rhs: Self) -> Self::Output {
         let number = self.number * rhs.number;
-        let unit = self.unit * rhs.unit;
+        let unit = (self.unit * rhs.unit)?;
+        // Only products in the dimension of the result are kept, as no others can be used to display it.
         let mut use_derived_unit = Vec::new();
         for lhs_d in &self.use_derived_unit {
             for rhs_d in &rhs.use_derived_unit {
-                use_derived_unit.push(lhs_d.clone() * rhs_d.clone());
+                let exponents = lhs_d.exponents.clone() * rhs_d.exponents.clone();
+                if product_symbol_fits(lhs_d, rhs_d) && exponents.is_ok_and(|e| e == unit) {
+                    use_derived_unit.extend(lhs_d.clone() * rhs_d.clone());
+                }
             }
         }
         for lhs_d in &self.use_derived_unit {
@@ -173,24 +314,32 @@ impl Mul for Quantity {
             .unique_by(|d| d.symbol.clone())
             .collect();
 
-        Quantity {
+        Ok(Quantity {
             number,
             unit,
             use_derived_unit,
-        }
+            use_format: None,
+        })
     }
 }
 
 impl Div for Quantity {
-    type Output = Self;
+    type Output = Result<Self, QuantityError>;
 
     fn div(self, rhs: Self) -> Self::Output {
-        let number = self.number / rhs.number;
-        let unit = self.unit / rhs.unit;
+        let number = self
+            .number
+            .checked_div(&rhs.number)
+            .ok_or(QuantityError::DivisionByZero)?;
+        let unit = (self.unit / rhs.unit)?;
+        // Only quotients in the dimension of the result are kept, as no others can be used to display it.
         let mut use_derived_unit = Vec::new();
         for lhs_d in &self.use_derived_unit {
-            for rhs_d in &rhs.use_derived_unit {
-                use_derived_unit.push(lhs_d.clone() / rhs_d.clone());
+            for rhs_d in rhs.use_derived_unit.iter().filter(|d| !d.scale.is_zero()) {
+                let exponents = lhs_d.exponents.clone() / rhs_d.exponents.clone();
+                if product_symbol_fits(lhs_d, rhs_d) && exponents.is_ok_and(|e| e == unit) {
+                    use_derived_unit.extend(lhs_d.clone() / rhs_d.clone());
+                }
             }
         }
         for lhs_d in &self.use_derived_unit {
@@ -204,10 +353,22 @@ impl Div for Quantity {
             .unique_by(|d| d.symbol.clone())
             .collect();
 
-        Quantity {
+        Ok(Quantity {
             number,
             unit,
             use_derived_unit,
+            use_format: None,
+        })
+    }
+}
+
+impl Neg for Quantity {
+    type Output = Self;
+
+    fn neg(self) -> Self::Output {
+        Quantity {
+            number: -self.number,
+            ..self
         }
     }
 }
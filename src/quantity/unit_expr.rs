@@ -0,0 +1,329 @@
+// Copyright 2024 eternal-flame-AD <yume@yumechi.jp>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use num_bigint::BigInt;
+use num_rational::BigRational;
+use num_traits::{One, Signed, ToPrimitive, Zero};
+use serde::Serialize;
+use thiserror::Error;
+
+use crate::{interpreter::limits::Limits, tokenizer::is_name_char};
+
+use super::{
+    exact_root,
+    units::{DerivedUnit, Unit, UnitCombo, UnitSystem},
+    QuantityError,
+};
+
+#[derive(Error, Debug, Clone, PartialEq, Serialize)]
+#[serde(tag = "reason", content = "value", rename_all = "snake_case")]
+pub enum UnitExprError {
+    #[error("Unexpected character '{0}' at position {1}")]
+    UnexpectedCharacter(char, usize),
+    #[error("Unexpected end of unit expression")]
+    UnexpectedEnd,
+    #[error("Unknown unit: {0}")]
+    UnknownUnit(String),
+    #[error("Raising to the power of {0} does not give integer exponents")]
+    NonIntegerExponent(BigRational),
+    #[error("Raising to the power of {0} does not give a rational scale")]
+    IrrationalScale(BigRational),
+    #[error("Unit with an offset cannot be used in a compound unit: {0}")]
+    OffsetInCompound(String),
+    #[error("Division by zero in unit expression")]
+    DivisionByZero,
+    #[error("Exponent out of range: {0}")]
+    ExponentOutOfRange(BigRational),
+    #[error("Unit exponent out of range")]
+    UnitExponentOverflow,
+    #[error("{0}")]
+    QuantityError(QuantityError),
+}
+
+impl From<QuantityError> for UnitExprError {
+    fn from(e: QuantityError) -> Self {
+        match e {
+            QuantityError::NonIntegerExponent(exponent) => {
+                UnitExprError::NonIntegerExponent(exponent)
+            }
+            QuantityError::ExponentOutOfRange(exponent) => {
+                UnitExprError::ExponentOutOfRange(exponent)
+            }
+            QuantityError::UnitExponentOverflow => UnitExprError::UnitExponentOverflow,
+            QuantityError::DivisionByZero => UnitExprError::DivisionByZero,
+            e => UnitExprError::QuantityError(e),
+        }
+    }
+}
+
+/// A parsed unit expression, before it is resolved against a [`UnitSystem`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum UnitExpr {
+    Unit(String),
+    Number(BigInt),
+    Mul(Box<UnitExpr>, Box<UnitExpr>),
+    Div(Box<UnitExpr>, Box<UnitExpr>),
+    Pow(Box<UnitExpr>, BigRational),
+}
+
+impl UnitExpr {
+    /// Parses a unit expression such as `kg*m^2/s^2` or `(m^2)^(1/2)`.
+    ///
+    /// Grammar:
+    ///
+    /// ```text
+    /// expr     := power (('*' | '/') power)*
+    /// power    := atom ('^' exponent)*
+    /// atom     := symbol | integer | '(' expr ')'
+    /// exponent := ['-'] integer | '(' ['-'] integer ['/' integer] ')'
+    /// ```
+    ///
+    /// Exponents whose numerator or denominator exceed [`Limits::max_exponent`] are refused.
+    pub fn parse(input: &str, limits: &Limits) -> Result<Self, UnitExprError> {
+        let mut parser = Parser {
+            chars: input.chars().collect(),
+            pos: 0,
+            max_exponent: limits.max_exponent,
+        };
+        let expr = parser.parse_expr()?;
+        match parser.peek() {
+            Some(c) => Err(UnitExprError::UnexpectedCharacter(c, parser.pos)),
+            None => Ok(expr),
+        }
+    }
+    /// Resolves the expression into its scale and base unit combination.
+    ///
+    /// Units with an offset are rejected, as they do not have a meaningful product.
+    /// Powers that would give a unit exponent above [`Limits::max_exponent`] or a scale above
+    /// [`Limits::max_number_bits`] are refused before they are computed.
+    pub fn resolve(
+        &self,
+        system: &UnitSystem,
+        limits: &Limits,
+    ) -> Result<(BigRational, UnitCombo), UnitExprError> {
+        match self {
+            UnitExpr::Unit(symbol) => match system.lookup_unit(symbol) {
+                Some(Unit::Base(base_unit)) => {
+                    let mut combo = UnitCombo::new();
+                    combo.push_base_unit(base_unit.clone(), 1);
+                    Ok((BigRational::one(), combo))
+                }
+                Some(Unit::Derived(derived_unit)) => {
+                    if !derived_unit.offset.is_zero() {
+                        return Err(UnitExprError::OffsetInCompound(symbol.clone()));
+                    }
+                    Ok((derived_unit.scale.clone(), derived_unit.exponents.clone()))
+                }
+                None => Err(UnitExprError::UnknownUnit(symbol.clone())),
+            },
+            UnitExpr::Number(n) => Ok((BigRational::from_integer(n.clone()), UnitCombo::new())),
+            UnitExpr::Mul(lhs, rhs) => {
+                let (lhs_scale, lhs_unit) = lhs.resolve(system, limits)?;
+                let (rhs_scale, rhs_unit) = rhs.resolve(system, limits)?;
+                Ok((lhs_scale * rhs_scale, (lhs_unit * rhs_unit)?))
+            }
+            UnitExpr::Div(lhs, rhs) => {
+                let (lhs_scale, lhs_unit) = lhs.resolve(system, limits)?;
+                let (rhs_scale, rhs_unit) = rhs.resolve(system, limits)?;
+                if rhs_scale.is_zero() {
+                    return Err(UnitExprError::DivisionByZero);
+                }
+                Ok((lhs_scale / rhs_scale, (lhs_unit / rhs_unit)?))
+            }
+            UnitExpr::Pow(base, exponent) => {
+                let (scale, unit) = base.resolve(system, limits)?;
+                let unit = unit.checked_pow(exponent)?;
+                if limits
+                    .max_exponent
+                    .is_some_and(|max| u64::from(unit.max_exponent()) > max)
+                {
+                    return Err(UnitExprError::ExponentOutOfRange(exponent.clone()));
+                }
+                if scale.is_zero() && exponent.is_negative() {
+                    return Err(UnitExprError::DivisionByZero);
+                }
+                // Check the size of the scale before computing it, as in `(km^1000)^1000`.
+                let scale_bits = scale.numer().bits().max(scale.denom().bits());
+                let power = exponent.numer().magnitude().to_u64().unwrap_or(u64::MAX);
+                if limits
+                    .max_number_bits
+                    .is_some_and(|max| scale_bits.saturating_sub(1).saturating_mul(power) > max)
+                {
+                    return Err(UnitExprError::ExponentOutOfRange(exponent.clone()));
+                }
+                let numer = exponent
+                    .numer()
+                    .to_i32()
+                    .ok_or_else(|| UnitExprError::IrrationalScale(exponent.clone()))?;
+                let denom = exponent
+                    .denom()
+                    .to_u32()
+                    .ok_or_else(|| UnitExprError::IrrationalScale(exponent.clone()))?;
+                let scale = exact_root(&scale.pow(numer), denom)
+                    .ok_or_else(|| UnitExprError::IrrationalScale(exponent.clone()))?;
+                Ok((scale, unit))
+            }
+        }
+    }
+}
+
+impl UnitSystem {
+    /// Resolves a unit expression into a derived unit, using the expression itself as the symbol.
+    ///
+    /// A lone unit resolves to itself, keeping its offset. Products, quotients and powers of units are
+    /// resolved against the base and derived units known to this system.
+    pub fn parse_unit_expr(
+        &self,
+        input: &str,
+        limits: &Limits,
+    ) -> Result<DerivedUnit, UnitExprError> {
+        let expr = UnitExpr::parse(input, limits)?;
+        if let UnitExpr::Unit(symbol) = &expr {
+            if let Some(Unit::Derived(derived_unit)) = self.lookup_unit(symbol) {
+                return Ok(derived_unit.into_owned());
+            }
+        }
+        let (scale, exponents) = expr.resolve(self, limits)?;
+        Ok(DerivedUnit {
+            symbol: input.to_string(),
+            offset: BigRational::zero(),
+            scale,
+            exponents,
+        })
+    }
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    max_exponent: Option<u64>,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+    fn next(&mut self) -> Option<char> {
+        let ch = self.peek();
+        if ch.is_some() {
+            self.pos += 1;
+        }
+        ch
+    }
+    fn expect(&mut self, expected: char) -> Result<(), UnitExprError> {
+        match self.next() {
+            Some(c) if c == expected => Ok(()),
+            Some(c) => Err(UnitExprError::UnexpectedCharacter(c, self.pos - 1)),
+            None => Err(UnitExprError::UnexpectedEnd),
+        }
+    }
+    fn parse_expr(&mut self) -> Result<UnitExpr, UnitExprError> {
+        let mut lhs = self.parse_power()?;
+        loop {
+            match self.peek() {
+                Some('*') => {
+                    self.next();
+                    lhs = UnitExpr::Mul(Box::new(lhs), Box::new(self.parse_power()?));
+                }
+                Some('/') => {
+                    self.next();
+                    lhs = UnitExpr::Div(Box::new(lhs), Box::new(self.parse_power()?));
+                }
+                _ => return Ok(lhs),
+            }
+        }
+    }
+    fn parse_power(&mut self) -> Result<UnitExpr, UnitExprError> {
+        let mut base = self.parse_atom()?;
+        while self.peek() == Some('^') {
+            self.next();
+            base = UnitExpr::Pow(Box::new(base), self.parse_exponent()?);
+        }
+        Ok(base)
+    }
+    fn parse_atom(&mut self) -> Result<UnitExpr, UnitExprError> {
+        match self.peek() {
+            Some('(') => {
+                self.next();
+                let expr = self.parse_expr()?;
+                self.expect(')')?;
+                Ok(expr)
+            }
+            Some('0'..='9') => Ok(UnitExpr::Number(self.parse_integer()?)),
+            Some(c) if is_name_char(c) => {
+                let mut symbol = String::new();
+                while let Some(c) = self.peek() {
+                    if !is_name_char(c) {
+                        break;
+                    }
+                    symbol.push(c);
+                    self.next();
+                }
+                Ok(UnitExpr::Unit(symbol))
+            }
+            Some(c) => Err(UnitExprError::UnexpectedCharacter(c, self.pos)),
+            None => Err(UnitExprError::UnexpectedEnd),
+        }
+    }
+    fn parse_integer(&mut self) -> Result<BigInt, UnitExprError> {
+        let mut n = BigInt::zero();
+        let mut digits = 0;
+        while let Some(d) = self.peek().and_then(|c| c.to_digit(10)) {
+            n = n * 10 + d;
+            digits += 1;
+            self.next();
+        }
+        match (digits, self.peek()) {
+            (0, Some(c)) => Err(UnitExprError::UnexpectedCharacter(c, self.pos)),
+            (0, None) => Err(UnitExprError::UnexpectedEnd),
+            _ => Ok(n),
+        }
+    }
+    fn parse_signed_integer(&mut self) -> Result<BigInt, UnitExprError> {
+        if self.peek() == Some('-') {
+            self.next();
+            return Ok(-self.parse_integer()?);
+        }
+        self.parse_integer()
+    }
+    /// Parses an exponent, refusing numerators and denominators above [`Limits::max_exponent`].
+    fn parse_exponent(&mut self) -> Result<BigRational, UnitExprError> {
+        if self.peek() != Some('(') {
+            let exponent = BigRational::from_integer(self.parse_signed_integer()?);
+            return self.check_exponent(exponent);
+        }
+        self.next();
+        let numer = self.parse_signed_integer()?;
+        let denom = if self.peek() == Some('/') {
+            self.next();
+            self.parse_integer()?
+        } else {
+            BigInt::one()
+        };
+        self.expect(')')?;
+        if denom.is_zero() {
+            return Err(UnitExprError::DivisionByZero);
+        }
+        self.check_exponent(BigRational::new(numer, denom))
+    }
+    fn check_exponent(&self, exponent: BigRational) -> Result<BigRational, UnitExprError> {
+        if let Some(max) = self.max_exponent {
+            let max = BigInt::from(max);
+            if exponent.numer().abs() > max || *exponent.denom() > max {
+                return Err(UnitExprError::ExponentOutOfRange(exponent));
+            }
+        }
+        Ok(exponent)
+    }
+}
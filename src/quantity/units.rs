@@ -13,23 +13,73 @@
 // limitations under the License.
 
 use std::{
-    collections::HashMap,
+    borrow::Cow,
+    collections::{HashMap, HashSet},
     fmt::{Debug, Display},
     ops::{Div, Mul, Neg},
 };
 
+use num_bigint::BigInt;
 use num_rational::BigRational;
+use num_traits::{One, Signed, ToPrimitive, Zero};
 use serde::{Deserialize, Serialize};
 
-#[derive(Clone)]
+use super::{exact_root, normalize, QuantityError};
+
+/// SI prefixes and their power of 10. Micro is written as `u`, or as `µ` (micro sign) or `μ` (Greek mu).
+pub const SI_PREFIXES: &[(&str, i32)] = &[
+    ("Q", 30),
+    ("R", 27),
+    ("Y", 24),
+    ("Z", 21),
+    ("E", 18),
+    ("P", 15),
+    ("T", 12),
+    ("G", 9),
+    ("M", 6),
+    ("k", 3),
+    ("h", 2),
+    ("da", 1),
+    ("d", -1),
+    ("c", -2),
+    ("m", -3),
+    ("u", -6),
+    ("µ", -6),
+    ("μ", -6),
+    ("n", -9),
+    ("p", -12),
+    ("f", -15),
+    ("a", -18),
+    ("z", -21),
+    ("y", -24),
+    ("r", -27),
+    ("q", -30),
+];
+
+/// IEC binary prefixes and their power of 2.
+pub const IEC_PREFIXES: &[(&str, u32)] = &[
+    ("Ki", 10),
+    ("Mi", 20),
+    ("Gi", 30),
+    ("Ti", 40),
+    ("Pi", 50),
+    ("Ei", 60),
+    ("Zi", 70),
+    ("Yi", 80),
+];
+
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
 pub struct UnitSystem {
     base_units: HashMap<String, BaseUnit>,
     derived_units: HashMap<String, DerivedUnit>,
+    prefixes: HashMap<String, BigRational>,
+    no_prefix: HashSet<String>,
 }
 
 pub enum Unit<'a> {
     Base(&'a BaseUnit),
-    Derived(&'a DerivedUnit),
+    /// A derived unit, which is owned if it was resolved from a prefix.
+    Derived(Cow<'a, DerivedUnit>),
 }
 
 impl Debug for UnitSystem {
@@ -38,9 +88,15 @@ impl Debug for UnitSystem {
         base_units.sort_by(|a, b| a.symbol.cmp(&b.symbol));
         let mut derived_units: Vec<&DerivedUnit> = self.derived_units.values().collect();
         derived_units.sort_by(|a, b| a.symbol.cmp(&b.symbol));
+        let mut prefixes: Vec<(&String, &BigRational)> = self.prefixes.iter().collect();
+        prefixes.sort_by(|a, b| a.1.cmp(b.1));
+        let mut no_prefix: Vec<&String> = self.no_prefix.iter().collect();
+        no_prefix.sort();
         f.debug_struct("UnitSystem")
             .field("base_units", &base_units)
             .field("derived_units", &derived_units)
+            .field("prefixes", &prefixes)
+            .field("no_prefix", &no_prefix)
             .finish()
     }
 }
@@ -52,16 +108,110 @@ impl Default for UnitSystem {
 }
 
 impl UnitSystem {
+    /// Creates a unit system with no units, and the built-in SI and IEC prefixes.
     pub fn new() -> Self {
+        let mut prefixes = HashMap::new();
+        for (symbol, exp) in SI_PREFIXES {
+            prefixes.insert(
+                symbol.to_string(),
+                BigRational::from_integer(BigInt::from(10)).pow(*exp),
+            );
+        }
+        for (symbol, exp) in IEC_PREFIXES {
+            prefixes.insert(
+                symbol.to_string(),
+                BigRational::from_integer(BigInt::from(2).pow(*exp)),
+            );
+        }
         UnitSystem {
             base_units: HashMap::new(),
             derived_units: HashMap::new(),
+            prefixes,
+            no_prefix: HashSet::new(),
         }
     }
-    pub fn lookup_unit(&self, symbol: &str) -> Option<Unit> {
+    /// Looks up a unit by symbol.
+    ///
+    /// Symbols that are not defined are tried as a prefix followed by a prefixable unit, such as `km` or `MJ`.
+    pub fn lookup_unit(&self, symbol: &str) -> Option<Unit<'_>> {
         self.lookup_base_unit(symbol)
             .map(Unit::Base)
-            .or_else(|| self.lookup_derived_unit(symbol).map(Unit::Derived))
+            .or_else(|| {
+                self.lookup_derived_unit(symbol)
+                    .map(|d| Unit::Derived(Cow::Borrowed(d)))
+            })
+            .or_else(|| {
+                self.lookup_prefixed_unit(symbol)
+                    .map(|d| Unit::Derived(Cow::Owned(d)))
+            })
+    }
+    /// Returns whether a unit with exactly this symbol has been defined, without resolving prefixes.
+    pub fn is_defined(&self, symbol: &str) -> bool {
+        self.base_units.contains_key(symbol) || self.derived_units.contains_key(symbol)
+    }
+    /// Resolves `<prefix><unit>` into a derived unit, preferring the longest matching prefix.
+    pub fn lookup_prefixed_unit(&self, symbol: &str) -> Option<DerivedUnit> {
+        let mut prefixes: Vec<(&String, &BigRational)> = self
+            .prefixes
+            .iter()
+            .filter(|(p, _)| symbol.len() > p.len() && symbol.starts_with(p.as_str()))
+            .collect();
+        prefixes.sort_by_key(|(p, _)| std::cmp::Reverse(p.len()));
+        prefixes.into_iter().find_map(|(prefix, scale)| {
+            self.prefixable_unit(&symbol[prefix.len()..])
+                .map(|unit| DerivedUnit {
+                    symbol: symbol.to_string(),
+                    offset: BigRational::zero(),
+                    scale: scale * unit.scale,
+                    exponents: unit.exponents,
+                })
+        })
+    }
+    /// Looks up a defined unit that can take a prefix, as a derived unit.
+    fn prefixable_unit(&self, symbol: &str) -> Option<DerivedUnit> {
+        if self.no_prefix.contains(symbol) {
+            return None;
+        }
+        if let Some(base_unit) = self.lookup_base_unit(symbol) {
+            let mut exponents = UnitCombo::new();
+            exponents.push_base_unit(base_unit.clone(), 1);
+            return Some(DerivedUnit {
+                symbol: symbol.to_string(),
+                offset: BigRational::zero(),
+                scale: BigRational::one(),
+                exponents,
+            });
+        }
+        self.lookup_derived_unit(symbol)
+            .filter(|d| d.offset.is_zero())
+            .cloned()
+    }
+    pub fn lookup_prefix(&self, symbol: &str) -> Option<&BigRational> {
+        self.prefixes.get(symbol)
+    }
+    pub fn push_prefix(&mut self, symbol: &str, scale: BigRational) {
+        self.prefixes.insert(symbol.to_string(), scale);
+    }
+    /// Returns all prefixes, sorted by scale.
+    pub fn prefixes(&self) -> Vec<(String, BigRational)> {
+        let mut prefixes: Vec<(String, BigRational)> = self
+            .prefixes
+            .iter()
+            .map(|(p, s)| (p.clone(), s.clone()))
+            .collect();
+        prefixes.sort_by(|a, b| a.1.cmp(&b.1).then_with(|| a.0.cmp(&b.0)));
+        prefixes
+    }
+    /// Normalizes the scales and offsets of all derived units and prefixes, see [`normalize`].
+    pub fn normalize(&mut self) -> Result<(), QuantityError> {
+        self.derived_units
+            .values_mut()
+            .try_for_each(DerivedUnit::normalize)?;
+        self.prefixes.values_mut().try_for_each(normalize)
+    }
+    /// Opts a unit out of being resolved with a prefix.
+    pub fn push_no_prefix(&mut self, symbol: &str) {
+        self.no_prefix.insert(symbol.to_string());
     }
     pub fn lookup_base_unit(&self, symbol: &str) -> Option<&BaseUnit> {
         self.base_units.get(symbol)
@@ -91,6 +241,84 @@ impl UnitSystem {
         derived_units.sort_by(|a, b| format!("{}", a.exponents).cmp(&format!("{}", b.exponents)));
         derived_units
     }
+    /// Picks the derived unit that gives the most readable number for a quantity in base units.
+    ///
+    /// Candidates are the base unit and coherent derived units (those with a scale of 1, such as `W`) whose
+    /// exponents match, the unit the quantity was `entered` in, and their variants with a prefix that is a power
+    /// of 1000. Other derived units such as `ft` or `gal` are only picked if the quantity was entered in them.
+    /// The unit with the smallest mantissa that is still at least 1 wins, so 1e-9 (mol) becomes 1 (nmol) and
+    /// 0.5 (m) becomes 500 (mm). Returns `None` if the base units are the best choice.
+    pub fn best_derived_unit(
+        &self,
+        number: &BigRational,
+        exponents: &UnitCombo,
+        entered: Option<&DerivedUnit>,
+    ) -> Option<DerivedUnit> {
+        if number.is_zero() || exponents.is_unitless() {
+            return None;
+        }
+        let entered = entered.filter(|d| d.exponents == *exponents && d.offset.is_zero());
+        // The unit that was entered, without its prefix, so that entering `mWh` also considers `kWh`.
+        let entered_root = entered.and_then(|d| {
+            if self.is_defined(&d.symbol) {
+                return Some(d.symbol.as_str());
+            }
+            self.prefixes
+                .keys()
+                .filter_map(|p| d.symbol.strip_prefix(p.as_str()))
+                .find(|root| self.is_defined(root))
+        });
+        let mut units: Vec<DerivedUnit> = entered.into_iter().cloned().collect();
+        // Only prefixes that are a power of 1000 are considered, so `cl` and `dam` are never picked.
+        let engineering_prefixes = SI_PREFIXES.iter().filter(|(_, exp)| exp % 3 == 0);
+        for symbol in self.base_units.keys().chain(self.derived_units.keys()) {
+            let coherent = self
+                .derived_units
+                .get(symbol)
+                .is_none_or(|d| d.scale.is_one());
+            if !coherent && entered_root != Some(symbol.as_str()) {
+                continue;
+            }
+            let Some(unit) = self.prefixable_unit(symbol) else {
+                continue;
+            };
+            if unit.exponents != *exponents {
+                continue;
+            }
+            for (prefix, _) in engineering_prefixes.clone() {
+                let prefixed = format!("{}{}", prefix, symbol);
+                if self.is_defined(&prefixed) {
+                    continue;
+                }
+                if let Some(d) = self.lookup_prefixed_unit(&prefixed) {
+                    units.push(d);
+                }
+            }
+            if self.derived_units.contains_key(symbol) {
+                units.push(unit);
+            }
+        }
+        let mut candidates: Vec<(BigRational, Option<&DerivedUnit>)> = units
+            .iter()
+            .filter(|d| d.scale.is_positive())
+            .map(|d| ((number / &d.scale).abs(), Some(d)))
+            .collect();
+        candidates.push((number.abs(), None));
+        candidates.sort_by(|(a, a_unit), (b, b_unit)| {
+            a.cmp(b).then_with(|| {
+                let symbol =
+                    |u: &Option<&DerivedUnit>| u.map(|u| (u.symbol.len(), u.symbol.clone()));
+                symbol(a_unit).cmp(&symbol(b_unit))
+            })
+        });
+
+        let one = BigRational::one();
+        candidates
+            .iter()
+            .find(|(mantissa, _)| *mantissa >= one)
+            .or_else(|| candidates.last())
+            .and_then(|(_, unit)| unit.cloned())
+    }
 }
 
 #[derive(Debug, PartialEq, Eq, Hash, Clone, Serialize, Deserialize)]
@@ -112,29 +340,68 @@ pub struct DerivedUnit {
     pub exponents: UnitCombo,
 }
 
+impl DerivedUnit {
+    /// Normalizes the scale and offset, see [`normalize`].
+    pub fn normalize(&mut self) -> Result<(), QuantityError> {
+        normalize(&mut self.scale)?;
+        normalize(&mut self.offset)
+    }
+    /// Raises the derived unit to a rational power.
+    ///
+    /// Returns `None` if the unit has an offset, or if the resulting exponents or scale are not exact.
+    pub fn checked_pow(&self, exponent: &BigRational) -> Option<Self> {
+        if exponent.is_one() {
+            return Some(self.clone());
+        }
+        if !self.offset.is_zero() || (self.scale.is_zero() && exponent < &BigRational::zero()) {
+            return None;
+        }
+        let scale = exact_root(
+            &self.scale.pow(exponent.numer().to_i32()?),
+            exponent.denom().to_u32()?,
+        )?;
+        let symbol = if self.symbol.contains(['*', '/', '^']) {
+            format!("({})", self.symbol)
+        } else {
+            self.symbol.clone()
+        };
+        let symbol = if exponent.is_integer() {
+            format!("{}^{}", symbol, exponent)
+        } else {
+            format!("{}^({})", symbol, exponent)
+        };
+        Some(DerivedUnit {
+            symbol,
+            offset: self.offset.clone(),
+            scale,
+            exponents: self.exponents.checked_pow(exponent).ok()?,
+        })
+    }
+}
+
 impl Mul for DerivedUnit {
-    type Output = Self;
+    type Output = Result<Self, QuantityError>;
 
     fn mul(self, rhs: Self) -> Self::Output {
-        DerivedUnit {
+        Ok(DerivedUnit {
             symbol: format!("{}*{}", self.symbol, rhs.symbol),
             offset: self.offset * rhs.scale.clone() + rhs.offset,
             scale: self.scale * rhs.scale,
-            exponents: self.exponents * rhs.exponents,
-        }
+            exponents: (self.exponents * rhs.exponents)?,
+        })
     }
 }
 
 impl Div for DerivedUnit {
-    type Output = Self;
+    type Output = Result<Self, QuantityError>;
 
     fn div(self, rhs: Self) -> Self::Output {
-        DerivedUnit {
+        Ok(DerivedUnit {
             symbol: format!("{}/{}", self.symbol, rhs.symbol),
             offset: self.offset * rhs.scale.clone() - rhs.offset,
             scale: self.scale / rhs.scale,
-            exponents: self.exponents / rhs.exponents,
-        }
+            exponents: (self.exponents / rhs.exponents)?,
+        })
     }
 }
 
@@ -191,7 +458,7 @@ impl Display for UnitCombo {
             write!(f, "1")?;
             return Ok(());
         }
-        exponents.sort_by(|a, b| b.exponent.cmp(&a.exponent));
+        exponents.sort_by_key(|e| std::cmp::Reverse(e.exponent));
         for exponent in exponents.iter() {
             if exponent.exponent == 1 {
                 if exponents.len() > 1 {
@@ -221,33 +488,55 @@ impl UnitCombo {
     pub fn is_unitless(&self) -> bool {
         self.0.is_empty()
     }
-    pub fn reduce(&self) -> Self {
+    /// Merges repeated base units and drops those with a zero exponent.
+    ///
+    /// Fails if a merged exponent does not fit in an `i32`.
+    pub fn reduce(&self) -> Result<Self, QuantityError> {
         let mut exponents: Vec<UnitExponent> = Vec::new();
         for component in self.0.iter() {
-            let mut found = false;
-            for exponent in exponents.iter_mut() {
-                if exponent.unit == component.unit {
-                    exponent.exponent += component.exponent;
-                    found = true;
-                    break;
+            match exponents.iter_mut().find(|e| e.unit == component.unit) {
+                Some(exponent) => {
+                    exponent.exponent = exponent
+                        .exponent
+                        .checked_add(component.exponent)
+                        .ok_or(QuantityError::UnitExponentOverflow)?;
                 }
+                None => exponents.push(component.clone()),
             }
-            if !found {
-                exponents.push(component.clone());
+        }
+        Ok(UnitCombo(
+            exponents.into_iter().filter(|e| e.exponent != 0).collect(),
+        ))
+    }
+    /// The largest unit exponent in the combination, in magnitude.
+    pub fn max_exponent(&self) -> u32 {
+        self.0
+            .iter()
+            .map(|e| e.exponent.unsigned_abs())
+            .max()
+            .unwrap_or(0)
+    }
+    /// Raises every exponent of the combination to a rational power.
+    ///
+    /// Fails if any resulting exponent would not be an integer, or would not fit in an `i32`.
+    pub fn checked_pow(&self, exponent: &BigRational) -> Result<Self, QuantityError> {
+        let mut new_exponents = self.0.clone();
+        for e in new_exponents.iter_mut() {
+            let scaled = exponent * BigInt::from(e.exponent);
+            if !scaled.is_integer() {
+                return Err(QuantityError::NonIntegerExponent(exponent.clone()));
             }
+            e.exponent = scaled
+                .to_integer()
+                .to_i32()
+                .ok_or(QuantityError::UnitExponentOverflow)?;
         }
-        UnitCombo(
-            exponents
-                .iter()
-                .filter(|e| e.exponent != 0)
-                .cloned()
-                .collect(),
-        )
+        UnitCombo(new_exponents).reduce()
     }
 }
 
 impl Mul for UnitCombo {
-    type Output = Self;
+    type Output = Result<Self, QuantityError>;
 
     fn mul(self, rhs: Self) -> Self::Output {
         let mut new_exponents = self.0.clone();
@@ -257,21 +546,24 @@ impl Mul for UnitCombo {
 }
 
 impl Neg for UnitCombo {
-    type Output = Self;
+    type Output = Result<Self, QuantityError>;
 
     fn neg(self) -> Self::Output {
         let mut new_exponents = self.0.clone();
         for exponent in new_exponents.iter_mut() {
-            exponent.exponent *= -1;
+            exponent.exponent = exponent
+                .exponent
+                .checked_neg()
+                .ok_or(QuantityError::UnitExponentOverflow)?;
         }
         UnitCombo(new_exponents).reduce()
     }
 }
 
 impl Div for UnitCombo {
-    type Output = Self;
+    type Output = Result<Self, QuantityError>;
 
     fn div(self, rhs: Self) -> Self::Output {
-        self * -rhs
+        self * (-rhs)?
     }
 }
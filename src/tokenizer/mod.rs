@@ -12,26 +12,28 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::{
-    fmt::Display,
-    io::{BufReader, Read},
-};
+use std::fmt::Display;
 pub mod parsing;
+pub mod reader;
 pub mod token;
 
+use serde::Serialize;
 use thiserror::Error;
 
 use token::Token;
 
-use self::parsing::parse_bigrational;
+use self::parsing::parse_bigrational_bounded;
 
-pub struct Tokenizer<R>
-where
-    R: std::io::Read,
-{
-    input: BufReader<R>,
-    unread_buffer: Option<char>,
+/// A tokenizer over a string, with arbitrary lookahead.
+///
+/// Names and literals are sliced directly out of the input, and any position can be saved with
+/// [`Tokenizer::checkpoint`] and returned to with [`Tokenizer::reset`].
+pub struct Tokenizer<'a> {
+    input: &'a str,
+    pos: usize,
     cursor: ReaderCursor,
+    token_start: ReaderCursor,
+    max_literal_exponent: Option<u64>,
 }
 
 #[derive(Error, Debug)]
@@ -40,9 +42,15 @@ pub enum TokenizerError {
     IOError(std::io::Error),
     #[error("Invalid character: {0}")]
     InvalidCharacter(char),
+    #[error("Unexpected end of input")]
+    UnexpectedEnd,
+    #[error("Invalid number: {0}")]
+    InvalidNumber(String),
+    #[error("Exponent too large in number: {0}")]
+    ExponentTooLarge(String),
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
 pub struct ReaderCursor {
     pub line: usize,
     pub column: usize,
@@ -64,154 +72,331 @@ impl ReaderCursor {
     pub fn new() -> Self {
         ReaderCursor { line: 1, column: 1 }
     }
+    fn advance(&mut self, ch: char) {
+        if ch == '\n' {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
+    }
+}
+
+/// Whether a character may appear in a unit, variable or macro name.
+///
+/// This includes ASCII letters, digits and `_`, as well as any non-ASCII letter or symbol such as `µ`, `Ω` or `°`.
+pub fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_' || !(c.is_ascii() || c.is_whitespace() || c.is_control())
+}
+
+/// A region of the input, from `start` up to but not including `end`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Span {
+    pub start: ReaderCursor,
+    pub end: ReaderCursor,
+}
+
+impl Display for Span {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}-{}", self.start, self.end)
+    }
 }
 
-impl<R: std::io::Read> Tokenizer<R> {
-    pub fn new(input: R) -> Self {
+impl Span {
+    /// Renders the line of `input` that the span starts on, with the span underlined by `^`.
+    ///
+    /// Spans that run past the end of the line are underlined up to the end of the line.
+    pub fn render(&self, input: &str) -> String {
+        let line = input
+            .lines()
+            .nth(self.start.line.saturating_sub(1))
+            .unwrap_or_default();
+        let line_len = line.chars().count();
+        let start = self.start.column.saturating_sub(1).min(line_len);
+        let end = if self.end.line == self.start.line {
+            self.end.column.saturating_sub(1).clamp(start, line_len)
+        } else {
+            line_len
+        };
+        format!(
+            "{}\n{}{}",
+            line,
+            " ".repeat(start),
+            "^".repeat((end - start).max(1))
+        )
+    }
+}
+
+/// A token together with where it was found in the input.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpannedToken {
+    pub token: Token,
+    pub span: Span,
+}
+
+/// A source of tokens, such as a [`Tokenizer`] over a string or a [`reader::ReaderTokenizer`] over a stream.
+pub trait TokenSource {
+    /// Reads the next token along with its span in the input.
+    fn parse_next_spanned_token(&mut self) -> Result<Option<SpannedToken>, TokenizerError>;
+    /// The span from the start of the last token read up to the current position.
+    fn current_span(&self) -> Span;
+}
+
+/// A saved tokenizer position, see [`Tokenizer::checkpoint`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Checkpoint {
+    pos: usize,
+    cursor: ReaderCursor,
+    token_start: ReaderCursor,
+}
+
+impl<'a> Tokenizer<'a> {
+    pub fn new(input: &'a str) -> Self {
+        Self::with_cursor(input, ReaderCursor::new())
+    }
+    /// Creates a tokenizer whose positions start at `cursor` instead of the first line and column.
+    pub fn with_cursor(input: &'a str, cursor: ReaderCursor) -> Self {
         Tokenizer {
-            input: BufReader::new(input),
-            unread_buffer: None,
-            cursor: ReaderCursor::new(),
+            input,
+            pos: 0,
+            cursor,
+            token_start: cursor,
+            max_literal_exponent: None,
         }
     }
-    fn next_char(&mut self) -> Result<Option<char>, std::io::Error> {
-        if let Some(ch) = self.unread_buffer {
-            self.unread_buffer = None;
-            return Ok(Some(ch));
-        }
-        let mut buf = [0; 1];
-        match self.input.read(&mut buf) {
-            Ok(0) => Ok(None),
-            Ok(_) => {
-                if buf[0] == b'\n' {
-                    self.cursor.line += 1;
-                    self.cursor.column = 1;
-                } else {
-                    self.cursor.column += 1;
-                }
-                Ok(Some(buf[0] as char))
-            }
-            Err(e) => Err(e),
+    /// Rejects number literals whose written exponent is larger than `max` in magnitude, such as `1e999999999`.
+    pub fn with_max_literal_exponent(mut self, max: Option<u64>) -> Self {
+        self.max_literal_exponent = max;
+        self
+    }
+    /// The part of the input that has not been read yet.
+    pub fn remaining(&self) -> &'a str {
+        &self.input[self.pos..]
+    }
+    /// The number of bytes of the input read so far.
+    pub fn offset(&self) -> usize {
+        self.pos
+    }
+    /// Returns the `n`th character after the current position without consuming anything.
+    pub fn peek_char(&self, n: usize) -> Option<char> {
+        self.remaining().chars().nth(n)
+    }
+    /// Returns the next token without consuming it.
+    pub fn peek_token(&mut self) -> Result<Option<SpannedToken>, TokenizerError> {
+        let checkpoint = self.checkpoint();
+        let token = self.parse_next_spanned_token();
+        self.reset(checkpoint);
+        token
+    }
+    /// Saves the current position so it can be returned to with [`Tokenizer::reset`].
+    pub fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            pos: self.pos,
+            cursor: self.cursor,
+            token_start: self.token_start,
         }
     }
-    fn next_char_non_whitespace(&mut self) -> Result<Option<char>, std::io::Error> {
-        loop {
-            match self.next_char()? {
-                Some(ch) if ch.is_whitespace() => {}
-                ch => return Ok(ch),
-            }
+    /// Backtracks to a position saved with [`Tokenizer::checkpoint`].
+    pub fn reset(&mut self, checkpoint: Checkpoint) {
+        self.pos = checkpoint.pos;
+        self.cursor = checkpoint.cursor;
+        self.token_start = checkpoint.token_start;
+    }
+    fn next_char(&mut self) -> Option<char> {
+        let ch = self.peek_char(0)?;
+        self.pos += ch.len_utf8();
+        self.cursor.advance(ch);
+        Some(ch)
+    }
+    /// Consumes characters while `f` holds, returning them as a slice of the input.
+    fn take_while(&mut self, f: impl Fn(char) -> bool) -> &'a str {
+        let start = self.pos;
+        while self.peek_char(0).is_some_and(&f) {
+            self.next_char();
         }
+        &self.input[start..self.pos]
     }
-    fn unread_char(&mut self, ch: char) {
-        if self.unread_buffer.is_some() {
-            panic!("Cannot unread more than one character");
+    /// Consumes the next character, failing unless it is `expected`.
+    fn expect_char(&mut self, expected: char) -> Result<(), TokenizerError> {
+        match self.next_char() {
+            Some(c) if c == expected => Ok(()),
+            Some(c) => Err(TokenizerError::InvalidCharacter(c)),
+            None => Err(TokenizerError::UnexpectedEnd),
         }
-        self.unread_buffer = Some(ch);
     }
     pub fn get_cursor(&self) -> ReaderCursor {
         self.cursor
     }
+    /// The span from the start of the last token read up to the current position.
+    ///
+    /// After an error, this covers the part of the input that failed to tokenize.
+    pub fn current_span(&self) -> Span {
+        Span {
+            start: self.token_start,
+            end: self.cursor,
+        }
+    }
     pub fn parse_next_token(&mut self) -> Result<Option<Token>, TokenizerError> {
-        let mut buf = String::new();
-        let ch = self
-            .next_char_non_whitespace()
-            .map_err(TokenizerError::IOError)?;
+        Ok(self.parse_next_spanned_token()?.map(|t| t.token))
+    }
+    /// Reads the next token along with its span in the input.
+    pub fn parse_next_spanned_token(&mut self) -> Result<Option<SpannedToken>, TokenizerError> {
+        self.take_while(char::is_whitespace);
+        self.token_start = self.cursor;
+        Ok(self.parse_token()?.map(|token| SpannedToken {
+            token,
+            span: self.current_span(),
+        }))
+    }
+    fn parse_token(&mut self) -> Result<Option<Token>, TokenizerError> {
+        let checkpoint = self.checkpoint();
+        let start = self.pos;
+        let Some(ch) = self.next_char() else {
+            return Ok(None);
+        };
         match ch {
-            Some('0'..='9' | '_') => {
-                buf.push(ch.unwrap());
-                while let Some(c) = self.next_char().map_err(TokenizerError::IOError)? {
-                    match c {
-                        '0'..='9' => buf.push(c),
-                        '.' | 'e' | 'E' | '_' | '-' => buf.push(c),
-                        _ => {
-                            self.unread_char(c);
-                            break;
-                        }
-                    }
-                }
-                Ok(Some(Token::Number(parse_bigrational(&buf)?)))
+            '0'..='9' | '_' => self.parse_number(checkpoint),
+            '-' if self.peek_char(0).is_some_and(|c| c.is_ascii_digit()) => {
+                self.parse_number(checkpoint)
             }
-            Some('(') => {
-                while let Some(c) = self.next_char().map_err(TokenizerError::IOError)? {
-                    match c {
-                        'a'..='z' | 'A'..='Z' | '0'..='9' | '/' | '*' | '_' => buf.push(c),
-                        ')' => break,
-                        _ => return Err(TokenizerError::InvalidCharacter(c)),
+            '(' => {
+                let mut depth = 0;
+                loop {
+                    match self.next_char() {
+                        Some('/' | '*' | '^' | '-') => {}
+                        Some(c) if is_name_char(c) => {}
+                        Some('(') => depth += 1,
+                        Some(')') if depth > 0 => depth -= 1,
+                        Some(')') => break,
+                        Some(c) => return Err(TokenizerError::InvalidCharacter(c)),
+                        None => return Err(TokenizerError::UnexpectedEnd),
                     }
                 }
-                Ok(Some(Token::Unit(buf)))
+                Ok(Some(Token::Unit(
+                    self.input[start + 1..self.pos - 1].to_string(),
+                )))
             }
-            Some('@') => {
-                while let Some(c) = self.next_char().map_err(TokenizerError::IOError)? {
-                    match c {
-                        'a'..='z' | 'A'..='Z' | '_' => buf.push(c),
-                        '(' => {
-                            break;
-                        }
-                        _ => return Err(TokenizerError::InvalidCharacter(c)),
-                    }
-                }
-                let macro_name = buf.clone();
-                buf.clear();
-                while let Some(c) = self.next_char().map_err(TokenizerError::IOError)? {
-                    match c {
-                        ')' => break,
-                        _ => buf.push(c),
+            '[' => {
+                let mut depth = 0;
+                loop {
+                    match self.next_char() {
+                        Some('[') => depth += 1,
+                        Some(']') if depth > 0 => depth -= 1,
+                        Some(']') => break,
+                        Some(_) => {}
+                        None => return Err(TokenizerError::UnexpectedEnd),
                     }
                 }
-                Ok(Some(Token::MacroInvoke((macro_name, buf))))
+                Ok(Some(Token::String(
+                    self.input[start + 1..self.pos - 1].to_string(),
+                )))
             }
-            Some('+') => Ok(Some(Token::Add)),
-            Some('-') => Ok(Some(Token::Sub)),
-            Some('*') => Ok(Some(Token::Mul)),
-            Some('/') => Ok(Some(Token::Div)),
-            Some('p') => Ok(Some(Token::Operator('p'))),
-            Some('n') => Ok(Some(Token::Operator('n'))),
-            Some('f') => Ok(Some(Token::Operator('f'))),
-            Some('c') => Ok(Some(Token::Operator('c'))),
-            Some('d') => Ok(Some(Token::Operator('d'))),
-            Some('r') => Ok(Some(Token::Operator('r'))),
-            Some('s') => Ok(Some(Token::Operator('s'))),
-            Some('U') => Ok(Some(Token::Operator('U'))),
-            Some('#') => {
-                while let Some(c) = self.next_char().map_err(TokenizerError::IOError)? {
-                    match c {
-                        '\r' | '\n' => break,
-                        _ => buf.push(c),
-                    }
-                }
-                Ok(Some(Token::Comment(buf)))
+            '@' => {
+                let name = self.take_while(|c| is_name_char(c) && !c.is_ascii_digit());
+                self.expect_char('(')?;
+                let args = self.take_while(|c| c != ')');
+                self.expect_char(')')?;
+                Ok(Some(Token::MacroInvoke((
+                    name.to_string(),
+                    args.to_string(),
+                ))))
             }
-            Some('>') => {
-                while let Some(c) = self.next_char().map_err(TokenizerError::IOError)? {
-                    match c {
-                        'A'..='Z' | 'a'..='z' | '0'..='9' | '_' => buf.push(c),
-                        _ => {
-                            self.unread_char(c);
-                            break;
-                        }
-                    }
-                }
-                Ok(Some(Token::VarStore(buf)))
+            '+' => Ok(Some(Token::Add)),
+            '-' => Ok(Some(Token::Sub)),
+            '*' => Ok(Some(Token::Mul)),
+            '/' => Ok(Some(Token::Div)),
+            '^' => Ok(Some(Token::Pow)),
+            '~' => Ok(Some(Token::Neg)),
+            'p' | 'n' | 'f' | 'c' | 'd' | 'r' | 'D' | 't' | 'y' | 'R' | 'z' | 'Y' | 's' | 'U'
+            | 'v' | 'k' | 'K' | 'x' | 'L' => Ok(Some(Token::Operator(ch))),
+            '#' => {
+                let comment = self.take_while(|c| c != '\r' && c != '\n');
+                Ok(Some(Token::Comment(comment.to_string())))
             }
-            Some('<') => {
-                while let Some(c) = self.next_char().map_err(TokenizerError::IOError)? {
-                    match c {
-                        'A'..='Z' | 'a'..='z' | '0'..='9' | '_' => buf.push(c),
-                        _ => {
-                            self.unread_char(c);
-                            break;
-                        }
+            // Without a variable name, `<` and `>` are comparisons.
+            '<' | '>' if !self.peek_char(0).is_some_and(is_name_char) => {
+                Ok(Some(Token::Operator(ch)))
+            }
+            '=' | '?' => Ok(Some(Token::Operator(ch))),
+            '>' => Ok(Some(Token::VarStore(
+                self.take_while(is_name_char).to_string(),
+            ))),
+            '<' => Ok(Some(Token::VarRecall(
+                self.take_while(is_name_char).to_string(),
+            ))),
+            _ => Err(TokenizerError::InvalidCharacter(ch)),
+        }
+    }
+}
+
+impl Tokenizer<'_> {
+    /// Reads a number literal starting at `checkpoint`, see [`parsing::parse_bigrational`] for the syntax.
+    ///
+    /// A `-` is only part of the literal as its leading sign, possibly after a `_` as in `_-5`, or directly after
+    /// the exponent marker `e`/`E`.
+    fn parse_number(&mut self, checkpoint: Checkpoint) -> Result<Option<Token>, TokenizerError> {
+        self.reset(checkpoint);
+        if self.peek_char(0) == Some('_') {
+            self.next_char();
+        }
+        if self.peek_char(0) == Some('-') {
+            self.next_char();
+        }
+        self.scan_unsigned_number();
+        while self.peek_char(0) == Some('|') {
+            self.next_char();
+            // A sign is not allowed here, but is kept in the literal so the error shows it.
+            if self.peek_char(0) == Some('-') {
+                self.next_char();
+            }
+            self.scan_unsigned_number();
+        }
+        Ok(Some(Token::Number(parse_bigrational_bounded(
+            &self.input[checkpoint.pos..self.pos],
+            self.max_literal_exponent,
+        )?)))
+    }
+    /// Consumes one unsigned part of a number literal.
+    fn scan_unsigned_number(&mut self) {
+        if self.peek_char(0) == Some('0') {
+            let digits: Option<fn(char) -> bool> = match self.peek_char(1) {
+                Some('x' | 'X') => Some(|c| c.is_ascii_hexdigit() || c == '_'),
+                Some('o' | 'O' | 'b' | 'B') => Some(|c| c.is_ascii_digit() || c == '_'),
+                _ => None,
+            };
+            if let Some(digits) = digits {
+                self.next_char();
+                self.next_char();
+                self.take_while(digits);
+                return;
+            }
+        }
+        while let Some(c) = self.peek_char(0) {
+            match c {
+                '0'..='9' | '.' | '_' => {}
+                'e' | 'E' => {
+                    self.next_char();
+                    if self.peek_char(0) == Some('-') {
+                        self.next_char();
                     }
+                    continue;
                 }
-                Ok(Some(Token::VarRecall(buf)))
+                _ => break,
             }
-            None => Ok(None),
-            _ => Err(TokenizerError::InvalidCharacter(ch.unwrap())),
+            self.next_char();
         }
     }
 }
 
+impl TokenSource for Tokenizer<'_> {
+    fn parse_next_spanned_token(&mut self) -> Result<Option<SpannedToken>, TokenizerError> {
+        Tokenizer::parse_next_spanned_token(self)
+    }
+    fn current_span(&self) -> Span {
+        Tokenizer::current_span(self)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -220,7 +405,7 @@ mod tests {
 
     #[test]
     fn test_tokenizer() {
-        let input = "1 2e3+ 3.4e5* (g) 4.5(ml) / 6_789 + 3.14".as_bytes();
+        let input = "1 2e3+ 3.4e5* (g) 4.5(ml) / 6_789 + 3.14";
         let mut tokenizer = Tokenizer::new(input);
         assert!(tokenizer
             .parse_next_token()
@@ -278,4 +463,210 @@ mod tests {
                 BigRational::from_f64(3.14).expect("Failed to parse number")
             )));
     }
+
+    #[test]
+    fn test_utf8() {
+        let input = "# ангстрем\n(Å) >Ω_1 @dé(x) (°C) \u{00ff}";
+        let mut tokenizer = Tokenizer::new(input);
+        let tokens = std::iter::from_fn(|| tokenizer.parse_next_spanned_token().transpose())
+            .take(5)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(
+            tokens.iter().map(|t| t.token.clone()).collect::<Vec<_>>(),
+            vec![
+                Token::Comment(" ангстрем".to_string()),
+                Token::Unit("Å".to_string()),
+                Token::VarStore("Ω_1".to_string()),
+                Token::MacroInvoke(("dé".to_string(), "x".to_string())),
+                Token::Unit("°C".to_string()),
+            ]
+        );
+        assert_eq!(tokens[2].span.start, ReaderCursor { line: 2, column: 5 });
+        assert_eq!(tokens[2].span.end, ReaderCursor { line: 2, column: 9 });
+
+        let mut tokenizer = reader::ReaderTokenizer::new(&b"(m\xff)"[..]);
+        assert!(matches!(
+            tokenizer.parse_next_spanned_token(),
+            Err(TokenizerError::IOError(_))
+        ));
+    }
+
+    #[test]
+    fn test_negative_literals() {
+        let number = |n: i64| Token::Number(BigRational::from_i64(n).unwrap());
+        let tokens = |input: &str| {
+            let mut tokenizer = Tokenizer::new(input);
+            std::iter::from_fn(|| tokenizer.parse_next_token().transpose())
+                .collect::<Result<Vec<_>, _>>()
+        };
+        assert_eq!(
+            tokens("-5 _5 - 5 1-2 2e-1 3~").unwrap(),
+            vec![
+                number(-5),
+                number(-5),
+                Token::Sub,
+                number(5),
+                number(1),
+                number(-2),
+                Token::Number(BigRational::new(1.into(), 5.into())),
+                number(3),
+                Token::Neg,
+            ]
+        );
+        assert_eq!(
+            tokens("_-5 _-5|2").unwrap(),
+            vec![
+                number(-5),
+                Token::Number(BigRational::new((-5).into(), 2.into()))
+            ]
+        );
+        assert!(tokens("_--5").is_err());
+        assert!(tokens("1e-").unwrap()[0].roughly_eq(&number(1)));
+    }
+
+    #[test]
+    fn test_comparisons() {
+        let mut tokenizer = Tokenizer::new("<a > <b< = >c [p] ?<");
+        let tokens = std::iter::from_fn(|| tokenizer.parse_next_token().transpose())
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(
+            tokens,
+            vec![
+                Token::VarRecall("a".to_string()),
+                Token::Operator('>'),
+                Token::VarRecall("b".to_string()),
+                Token::Operator('<'),
+                Token::Operator('='),
+                Token::VarStore("c".to_string()),
+                Token::String("p".to_string()),
+                Token::Operator('?'),
+                Token::Operator('<'),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_number_literals() {
+        let ratio = |n: i64, d: i64| BigRational::new(n.into(), d.into());
+        let numbers = |input: &str| {
+            let mut tokenizer = Tokenizer::new(input);
+            std::iter::from_fn(|| tokenizer.parse_next_token().transpose())
+                .map(|t| match t? {
+                    Token::Number(n) => Ok(n),
+                    t => panic!("expected a number, got {:?}", t),
+                })
+                .collect::<Result<Vec<_>, _>>()
+        };
+        assert_eq!(
+            numbers("1|3 -2|6 2|1|4 -1|1|2 1.5|3 0x1F 0X_ff -0o17 0b1010 0x10|0b11").unwrap(),
+            vec![
+                ratio(1, 3),
+                ratio(-1, 3),
+                ratio(9, 4),
+                ratio(-3, 2),
+                ratio(1, 2),
+                ratio(31, 1),
+                ratio(255, 1),
+                ratio(-15, 1),
+                ratio(10, 1),
+                ratio(16, 3),
+            ]
+        );
+        assert!(matches!(
+            numbers("1|0"),
+            Err(TokenizerError::InvalidNumber(s)) if s == "1|0"
+        ));
+        assert!(matches!(
+            numbers("1|-3"),
+            Err(TokenizerError::InvalidNumber(s)) if s == "1|-3"
+        ));
+        assert!(matches!(
+            numbers("1|0x"),
+            Err(TokenizerError::InvalidNumber(s)) if s == "1|0x"
+        ));
+        assert!(matches!(
+            numbers("0b102"),
+            Err(TokenizerError::InvalidNumber(s)) if s == "0b102"
+        ));
+        assert!(matches!(
+            numbers("1|2.3.4"),
+            Err(TokenizerError::InvalidNumber(s)) if s == "1|2.3.4"
+        ));
+        assert!(numbers("1|2|3|4").is_err());
+        assert!(numbers("0x").is_err());
+        assert!(numbers("1|").is_err());
+        assert!(numbers("1e5.5").is_err());
+
+        let mut tokenizer = Tokenizer::new("0b101f");
+        tokenizer.parse_next_token().unwrap();
+        assert_eq!(
+            tokenizer.parse_next_token().unwrap(),
+            Some(Token::Operator('f'))
+        );
+    }
+
+    #[test]
+    fn test_lookahead() {
+        let mut tokenizer = Tokenizer::new("1 (m) @base(s)");
+        assert_eq!(tokenizer.peek_char(2), Some('('));
+        let start = tokenizer.checkpoint();
+        tokenizer.parse_next_token().unwrap();
+        let peeked = tokenizer.peek_token().unwrap().unwrap();
+        assert_eq!(peeked.token, Token::Unit("m".to_string()));
+        assert_eq!(tokenizer.parse_next_spanned_token().unwrap(), Some(peeked));
+        assert_eq!(tokenizer.remaining(), " @base(s)");
+        tokenizer.reset(start);
+        assert_eq!(tokenizer.remaining(), "1 (m) @base(s)");
+        assert_eq!(tokenizer.get_cursor(), ReaderCursor::new());
+    }
+
+    #[test]
+    fn test_reader() {
+        let input = "1 # one\n@derived(\n  x) (m\n";
+        let mut tokenizer = reader::ReaderTokenizer::new(input.as_bytes());
+        let mut next = || {
+            tokenizer
+                .parse_next_spanned_token()
+                .map(|t| t.map(|t| t.token))
+        };
+        assert!(next()
+            .unwrap()
+            .unwrap()
+            .roughly_eq(&Token::Number(BigRational::from_i64(1).unwrap())));
+        assert_eq!(next().unwrap(), Some(Token::Comment(" one".to_string())));
+        assert_eq!(
+            next().unwrap(),
+            Some(Token::MacroInvoke((
+                "derived".to_string(),
+                "\n  x".to_string()
+            )))
+        );
+        assert!(matches!(
+            next(),
+            Err(TokenizerError::InvalidCharacter('\n'))
+        ));
+        assert_eq!(
+            tokenizer.current_span().start,
+            ReaderCursor { line: 3, column: 6 }
+        );
+    }
+
+    #[test]
+    fn test_no_panic() {
+        let alphabet = [
+            "0", "_", "-", ".", "e", "|", "x", "(", ")", "@", "#", "<", ">", "=", "?", "a", "µ",
+            " ", "\n",
+        ];
+        for a in alphabet {
+            for b in alphabet {
+                for c in alphabet {
+                    let input = format!("{}{}{}", a, b, c);
+                    let mut tokenizer = Tokenizer::new(&input);
+                    while let Ok(Some(_)) = tokenizer.parse_next_token() {}
+                }
+            }
+        }
+    }
 }
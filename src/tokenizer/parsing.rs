@@ -15,26 +15,93 @@
 use super::TokenizerError;
 use num_bigint::{BigInt, BigUint};
 use num_rational::BigRational;
-use num_traits::pow::Pow;
+use num_traits::{pow::Pow, CheckedDiv};
 
 use std::collections::VecDeque;
 
+/// Parses a number literal into an exact rational.
+///
+/// Besides decimals with an optional exponent, such as `1.5e-3`, this accepts:
+/// - integers in another radix: `0x1F`, `0o17` and `0b1010`
+/// - exact fractions: `1|3`
+/// - mixed numbers, as whole part, numerator and denominator: `2|1|4`
+///
+/// A leading `-`, or `_` as in dc, makes the number negative. Any other `_` is a digit separator.
+/// `_-5`, which older versions read as -5, is still read as -5.
 pub fn parse_bigrational(s: &str) -> Result<BigRational, TokenizerError> {
+    parse_bigrational_bounded(s, None)
+}
+
+/// Like [`parse_bigrational`], but fails with [`TokenizerError::ExponentTooLarge`] instead of computing a power of 10
+/// whose written exponent is larger than `max_exponent` in magnitude.
+pub fn parse_bigrational_bounded(
+    s: &str,
+    max_exponent: Option<u64>,
+) -> Result<BigRational, TokenizerError> {
+    let (is_negative, unsigned) = match s.strip_prefix("_-").or_else(|| s.strip_prefix(['-', '_']))
+    {
+        Some(rest) => (true, rest),
+        None => (false, s),
+    };
+
+    // Errors in any part, such as the sign in `1|-3` or the digit in `0b102`, report the whole literal.
+    let invalid = || TokenizerError::InvalidNumber(s.to_string());
+    let parts = unsigned
+        .split('|')
+        .map(|part| match parse_unsigned(part, max_exponent) {
+            _ if part.starts_with('-') => Err(invalid()),
+            Err(TokenizerError::ExponentTooLarge(literal)) => {
+                Err(TokenizerError::ExponentTooLarge(literal))
+            }
+            Err(_) => Err(invalid()),
+            result => result,
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let number = match parts.as_slice() {
+        [n] => n.clone(),
+        [numer, denom] => numer.checked_div(denom).ok_or_else(invalid)?,
+        [whole, numer, denom] => whole + numer.checked_div(denom).ok_or_else(invalid)?,
+        _ => return Err(invalid()),
+    };
+
+    Ok(if is_negative { -number } else { number })
+}
+
+/// Parses one unsigned part of a number literal, either an integer with a radix prefix or a decimal.
+fn parse_unsigned(s: &str, max_exponent: Option<u64>) -> Result<BigRational, TokenizerError> {
+    let radix = match s.get(..2) {
+        Some("0x" | "0X") => 16,
+        Some("0o" | "0O") => 8,
+        Some("0b" | "0B") => 2,
+        _ => return parse_decimal(s, max_exponent),
+    };
+    let digits = s[2..].chars().filter(|c| *c != '_').collect::<String>();
+    if digits.chars().any(|c| !c.is_digit(radix)) {
+        return Err(TokenizerError::InvalidNumber(s.to_string()));
+    }
+    BigInt::parse_bytes(digits.as_bytes(), radix)
+        .map(BigRational::from_integer)
+        .ok_or_else(|| TokenizerError::InvalidNumber(s.to_string()))
+}
+
+fn parse_decimal(s: &str, max_exponent: Option<u64>) -> Result<BigRational, TokenizerError> {
     let mut numerator = BigInt::from(0);
     let mut is_decimal = false;
     let mut decimal_places = 0;
-    let mut is_negative = false;
     let mut is_exponent = false;
     let mut exponent = BigInt::from(0);
     let mut exponent_is_negative = false;
 
-    let mut s = s.chars().filter(|c| *c != '_').collect::<VecDeque<_>>();
-
-    if s[0] == '-' {
-        is_negative = true;
-        s.pop_front();
+    if !s
+        .chars()
+        .take_while(|c| !matches!(c, 'e' | 'E'))
+        .any(|c| c.is_ascii_digit())
+    {
+        return Err(TokenizerError::InvalidNumber(s.to_string()));
     }
 
+    let literal = s;
+    let mut s = s.chars().filter(|c| *c != '_').collect::<VecDeque<_>>();
     while let Some(ch) = s.pop_front() {
         match ch {
             '0'..='9' => {
@@ -57,7 +124,7 @@ pub fn parse_bigrational(s: &str) -> Result<BigRational, TokenizerError> {
                 }
             }
             '.' => {
-                if is_decimal {
+                if is_decimal || is_exponent {
                     return Err(TokenizerError::InvalidCharacter(ch));
                 }
                 is_decimal = true;
@@ -79,8 +146,8 @@ pub fn parse_bigrational(s: &str) -> Result<BigRational, TokenizerError> {
         }
     }
 
-    if is_negative {
-        numerator = -numerator;
+    if max_exponent.is_some_and(|max| exponent > BigInt::from(max)) {
+        return Err(TokenizerError::ExponentTooLarge(literal.to_string()));
     }
     if exponent_is_negative {
         exponent = -exponent;
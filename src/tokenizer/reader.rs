@@ -0,0 +1,91 @@
+// Copyright 2024 eternal-flame-AD <yume@yumechi.jp>
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::io::{BufRead, BufReader, Read};
+
+use super::{ReaderCursor, Span, SpannedToken, TokenSource, Tokenizer, TokenizerError};
+
+/// A tokenizer that streams its input from a [`Read`], one line at a time.
+///
+/// Each line is tokenized with a [`Tokenizer`]. Tokens that continue past the end of a line, such as a macro
+/// whose arguments span several lines, cause further lines to be read until the token is complete.
+pub struct ReaderTokenizer<R: Read> {
+    input: BufReader<R>,
+    buffer: String,
+    pos: usize,
+    cursor: ReaderCursor,
+    token_start: ReaderCursor,
+    eof: bool,
+    max_literal_exponent: Option<u64>,
+}
+
+impl<R: Read> ReaderTokenizer<R> {
+    pub fn new(input: R) -> Self {
+        ReaderTokenizer {
+            input: BufReader::new(input),
+            buffer: String::new(),
+            pos: 0,
+            cursor: ReaderCursor::new(),
+            token_start: ReaderCursor::new(),
+            eof: false,
+            max_literal_exponent: None,
+        }
+    }
+    /// See [`Tokenizer::with_max_literal_exponent`].
+    pub fn with_max_literal_exponent(mut self, max: Option<u64>) -> Self {
+        self.max_literal_exponent = max;
+        self
+    }
+    /// Appends the next line of the input to the buffer, discarding the part that was already tokenized.
+    fn read_line(&mut self) -> Result<(), TokenizerError> {
+        self.buffer.drain(..self.pos);
+        self.pos = 0;
+        if self
+            .input
+            .read_line(&mut self.buffer)
+            .map_err(TokenizerError::IOError)?
+            == 0
+        {
+            self.eof = true;
+        }
+        Ok(())
+    }
+    pub fn get_cursor(&self) -> ReaderCursor {
+        self.cursor
+    }
+}
+
+impl<R: Read> TokenSource for ReaderTokenizer<R> {
+    fn parse_next_spanned_token(&mut self) -> Result<Option<SpannedToken>, TokenizerError> {
+        loop {
+            let mut tokenizer = Tokenizer::with_cursor(&self.buffer[self.pos..], self.cursor)
+                .with_max_literal_exponent(self.max_literal_exponent);
+            let result = tokenizer.parse_next_spanned_token();
+            if !self.eof && matches!(result, Ok(None) | Err(TokenizerError::UnexpectedEnd)) {
+                self.read_line()?;
+                continue;
+            }
+            self.pos += tokenizer.offset();
+            self.cursor = tokenizer.get_cursor();
+            self.token_start = tokenizer.current_span().start;
+            return result;
+        }
+    }
+    fn current_span(&self) -> Span {
+        Span {
+            start: self.token_start,
+            end: self.cursor,
+        }
+    }
+}
@@ -12,17 +12,25 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use std::fmt::Display;
+
 use num_rational::BigRational;
 use num_traits::ToPrimitive;
 
-#[derive(Debug, PartialEq)]
+use crate::quantity::format::NumberFormat;
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum Token {
     Number(BigRational),
     Unit(String),
+    /// A string literal, written in brackets as `[...]`.
+    String(String),
     Add,
     Sub,
     Mul,
     Div,
+    Pow,
+    Neg,
     VarStore(String),
     VarRecall(String),
     Operator(char),
@@ -35,12 +43,36 @@ impl Token {
         match (self, other) {
             (Token::Number(a), Token::Number(b)) => a.to_f64().unwrap() == b.to_f64().unwrap(),
             (Token::Unit(a), Token::Unit(b)) => a == b,
+            (Token::String(a), Token::String(b)) => a == b,
             (Token::Add, Token::Add) => true,
             (Token::Sub, Token::Sub) => true,
             (Token::Mul, Token::Mul) => true,
             (Token::Div, Token::Div) => true,
+            (Token::Pow, Token::Pow) => true,
+            (Token::Neg, Token::Neg) => true,
             (Token::Operator(a), Token::Operator(b)) => a == b,
             _ => false,
         }
     }
 }
+
+impl Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Token::Number(n) => write!(f, "{}", NumberFormat::default().format(n)),
+            Token::Unit(u) => write!(f, "({})", u),
+            Token::String(s) => write!(f, "[{}]", s),
+            Token::Add => write!(f, "+"),
+            Token::Sub => write!(f, "-"),
+            Token::Mul => write!(f, "*"),
+            Token::Div => write!(f, "/"),
+            Token::Pow => write!(f, "^"),
+            Token::Neg => write!(f, "~"),
+            Token::VarStore(name) => write!(f, ">{}", name),
+            Token::VarRecall(name) => write!(f, "<{}", name),
+            Token::Operator(c) => write!(f, "{}", c),
+            Token::MacroInvoke((name, args)) => write!(f, "@{}({})", name, args),
+            Token::Comment(c) => write!(f, "#{}", c),
+        }
+    }
+}
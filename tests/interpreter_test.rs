@@ -1,16 +1,32 @@
-use std::sync::Mutex;
-
-use num_traits::ToPrimitive;
+use num_rational::BigRational;
+use num_traits::{One, ToPrimitive};
 use unitdc::{
-    interpreter::Interpreter,
-    quantity::units::{BaseUnit, UnitCombo, UnitExponent},
+    interpreter::{
+        include::{FsResolver, IncludeError, MapResolver},
+        limits::{Limit, Limits},
+        output::{Definition, FnSink, JsonLinesSink, TextSink, WarningKind},
+        value::Value,
+        Interpreter, InterpreterError, InterpreterResult, Output,
+    },
+    quantity::{
+        format::{Notation, NumberFormat},
+        unit_expr::UnitExprError,
+        units::{BaseUnit, UnitCombo, UnitExponent},
+        QuantityError,
+    },
 };
 
+/// Whether an output only reports something being defined, rather than printed.
+fn is_definition(output: &Output) -> bool {
+    matches!(
+        output,
+        Output::UnitDefined(_) | Output::MacroDefined { .. } | Output::VariableAssigned { .. }
+    )
+}
+
 #[test]
 fn test_interpreter() {
-    let outputs = Mutex::new(Vec::new());
-    let output_fn = |output| outputs.lock().unwrap().push(output);
-    let mut interpreter = Interpreter::new(Box::new(output_fn));
+    let mut interpreter = Interpreter::new(Vec::new());
     interpreter
         .run_str("@base(m)")
         .expect("command should succeed");
@@ -21,7 +37,7 @@ fn test_interpreter() {
         .run_str("2 (km) p")
         .expect("command should succeed");
 
-    let output = outputs.lock().unwrap().pop().expect("output should exist");
+    let output = interpreter.sink_mut().pop().expect("output should exist");
     match output {
         unitdc::interpreter::Output::Quantity(q) => {
             assert_eq!(q.number.to_f64().unwrap(), 2000.0);
@@ -41,7 +57,7 @@ fn test_interpreter() {
     interpreter
         .run_str("2 (km) 1 (m) + p")
         .expect("command should succeed");
-    let output = outputs.lock().unwrap().pop().expect("output should exist");
+    let output = interpreter.sink_mut().pop().expect("output should exist");
     match output {
         unitdc::interpreter::Output::Quantity(q) => {
             assert_eq!(q.number.to_f64().unwrap(), 2001.0);
@@ -61,9 +77,7 @@ fn test_interpreter() {
 
 #[test]
 fn test_warn_confusing_units() {
-    let outputs = Mutex::new(Vec::new());
-    let output_fn = |output| outputs.lock().unwrap().push(output);
-    let mut interpreter = Interpreter::new(Box::new(output_fn));
+    let mut interpreter = Interpreter::new(Vec::new());
     interpreter
         .run_str("@base(K)")
         .expect("command should succeed");
@@ -76,18 +90,26 @@ fn test_warn_confusing_units() {
         .run_str("0 (degC) 0 (degC) + p")
         .expect("command should succeed");
 
-    let output = outputs.lock().unwrap().pop().expect("output should exist");
-    let msg = outputs.lock().unwrap().pop().expect("output should exist");
+    let output = interpreter.sink_mut().pop().expect("output should exist");
+    let msg = interpreter.sink_mut().pop().expect("output should exist");
 
     match msg {
-        unitdc::interpreter::Output::Message(e) => {
+        unitdc::interpreter::Output::Warning(w) => {
             assert_eq!(
-                e.to_ascii_lowercase().contains("warning"),
-                true,
+                w.kind,
+                WarningKind::OffsetUnitsCombined {
+                    base_unit: BaseUnit {
+                        symbol: "K".to_string(),
+                    },
+                }
+            );
+            assert_eq!(w.units, vec!["degC", "degC"]);
+            assert!(
+                w.to_string().to_ascii_lowercase().contains("warning"),
                 "output should contain 'warning'"
             );
         }
-        _ => panic!("output should be a message"),
+        _ => panic!("output should be a warning"),
     }
 
     match output {
@@ -114,17 +136,1241 @@ fn test_warn_confusing_units() {
         .run_str("0 (degC) 1 (m) * 0 (degC) 1 (m) * + p")
         .expect("command should succeed");
 
-    let _ = outputs.lock().unwrap().pop().expect("output should exist");
-    let msg = outputs.lock().unwrap().pop().expect("output should exist");
+    let _ = interpreter.sink_mut().pop().expect("output should exist");
+    let msg = interpreter.sink_mut().pop().expect("output should exist");
 
     match msg {
-        unitdc::interpreter::Output::Message(e) => {
+        unitdc::interpreter::Output::Warning(w) => {
             assert_eq!(
-                e.to_ascii_lowercase().contains("warning"),
-                true,
+                w.kind,
+                WarningKind::OffsetUnitsCombined {
+                    base_unit: BaseUnit {
+                        symbol: "K".to_string(),
+                    },
+                }
+            );
+            assert_eq!(w.units, vec!["degC", "degC"]);
+            assert!(
+                w.to_string().to_ascii_lowercase().contains("warning"),
                 "output should contain 'warning'"
             );
         }
-        _ => panic!("output should be a message"),
+        _ => panic!("output should be a warning"),
+    }
+}
+
+#[test]
+fn test_compound_units() {
+    let mut interpreter = Interpreter::new(Vec::new());
+    interpreter
+        .run_str("@base(m) @base(s) @base(g)")
+        .expect("command should succeed");
+    interpreter
+        .run_str("0 (m) 1e3 @derived(km) 0 (s) 3600 @derived(h) 0 (g) 1e3 @derived(kg)")
+        .expect("command should succeed");
+
+    interpreter
+        .run_str("36 (km/h) (m/s) p")
+        .expect("command should succeed");
+    let output = interpreter.sink_mut().pop().expect("output should exist");
+    match output {
+        unitdc::interpreter::Output::Quantity(q) => {
+            assert_eq!(q.number_in_derived_unit().unwrap().to_f64().unwrap(), 10.0);
+            assert_eq!(q.to_string(), "10 (m/s)");
+        }
+        _ => panic!("output should be a quantity"),
+    }
+
+    interpreter
+        .run_str("2 (kg*m^2/s^2) p")
+        .expect("command should succeed");
+    let output = interpreter.sink_mut().pop().expect("output should exist");
+    match output {
+        unitdc::interpreter::Output::Quantity(q) => {
+            assert_eq!(q.number.to_f64().unwrap(), 2000.0);
+            let mut expected = UnitCombo::new();
+            expected.push_base_unit(
+                BaseUnit {
+                    symbol: "g".to_string(),
+                },
+                1,
+            );
+            expected.push_base_unit(
+                BaseUnit {
+                    symbol: "m".to_string(),
+                },
+                2,
+            );
+            expected.push_base_unit(
+                BaseUnit {
+                    symbol: "s".to_string(),
+                },
+                -2,
+            );
+            assert_eq!(q.unit, expected);
+        }
+        _ => panic!("output should be a quantity"),
+    }
+
+    interpreter
+        .run_str("1 ((km^2)^(1/2)) (m) p")
+        .expect("command should succeed");
+    let output = interpreter.sink_mut().pop().expect("output should exist");
+    match output {
+        unitdc::interpreter::Output::Quantity(q) => {
+            assert_eq!(q.number.to_f64().unwrap(), 1000.0);
+        }
+        _ => panic!("output should be a quantity"),
+    }
+
+    assert!(interpreter.run_str("1 (m^(1/2))").is_err());
+    assert!(interpreter.run_str("1 (m/furlong)").is_err());
+}
+
+#[test]
+fn test_pow() {
+    let mut interpreter = Interpreter::new(Vec::new());
+    interpreter
+        .run_str("@base(m) 0 (m) 1e3 @derived(km)")
+        .expect("command should succeed");
+
+    interpreter
+        .run_str("3 (km) 2 ^ p")
+        .expect("command should succeed");
+    let output = interpreter.sink_mut().pop().expect("output should exist");
+    match output {
+        unitdc::interpreter::Output::Quantity(q) => {
+            assert_eq!(q.number.to_f64().unwrap(), 9e6);
+            assert_eq!(q.to_string(), "9 (km^2)");
+        }
+        _ => panic!("output should be a quantity"),
+    }
+
+    interpreter.run_str("v p").expect("command should succeed");
+    let output = interpreter.sink_mut().pop().expect("output should exist");
+    match output {
+        unitdc::interpreter::Output::Quantity(q) => {
+            assert_eq!(q.number.to_f64().unwrap(), 3000.0);
+            assert!(q.number.is_integer(), "perfect squares should stay exact");
+        }
+        _ => panic!("output should be a quantity"),
+    }
+
+    interpreter
+        .run_str("2 v p")
+        .expect("command should succeed");
+    let output = interpreter.sink_mut().pop().expect("output should exist");
+    match output {
+        unitdc::interpreter::Output::Quantity(q) => {
+            assert!((q.number.to_f64().unwrap() - std::f64::consts::SQRT_2).abs() < 1e-15);
+        }
+        _ => panic!("output should be a quantity"),
+    }
+
+    interpreter.run_str("c").expect("command should succeed");
+    assert!(interpreter.run_str("2 (m) v").is_err());
+    assert!(interpreter.run_str("2 (m) 2 (m) ^").is_err());
+    assert!(interpreter.run_str("-4 v").is_err());
+    assert!(interpreter.run_str("0 -1 ^").is_err());
+
+    // A huge derived unit scale must not be raised to a large power, even when the number itself is small.
+    interpreter
+        .run_str("0 (m) 1e4096 @derived(X)")
+        .expect("command should succeed");
+    let result = interpreter.run_str("1e-4096 (X) 1000 ^ p");
+    assert!(matches!(
+        result.as_ref().map_err(|e| e.inner()),
+        Err(InterpreterError::LimitExceeded {
+            limit: Limit::NumberBits,
+            ..
+        })
+    ));
+}
+
+#[test]
+fn test_number_format() {
+    let mut interpreter = Interpreter::new(Vec::new());
+    interpreter
+        .run_str("@base(mol) 0 (mol) 1e-12 @derived(pmol)")
+        .expect("command should succeed");
+
+    interpreter
+        .run_str("1e400 (mol) p 1 3 / (pmol) p")
+        .expect("command should succeed");
+    let third = interpreter.sink_mut().pop().expect("output should exist");
+    let huge = interpreter.sink_mut().pop().expect("output should exist");
+    match (huge, third) {
+        (
+            unitdc::interpreter::Output::Quantity(huge),
+            unitdc::interpreter::Output::Quantity(third),
+        ) => {
+            assert_eq!(huge.to_string(), "1e400 (mol)");
+            assert_eq!(third.to_string(), "0.333333333333333 (pmol)");
+        }
+        _ => panic!("output should be quantities"),
+    }
+
+    interpreter
+        .set_number_format(NumberFormat {
+            notation: Notation::Fraction,
+            precision: 0,
+        })
+        .expect("precision should be within the limits");
+    interpreter.run_str("p").expect("command should succeed");
+    let output = interpreter.sink_mut().pop().expect("output should exist");
+    match output {
+        unitdc::interpreter::Output::Quantity(q) => {
+            assert_eq!(q.to_string(), "1/3 (pmol)");
+        }
+        _ => panic!("output should be a quantity"),
+    }
+
+    let result = interpreter.set_number_format(NumberFormat {
+        notation: Notation::Scientific,
+        precision: u32::MAX,
+    });
+    assert!(matches!(
+        result,
+        Err(InterpreterError::LimitExceeded {
+            limit: Limit::Precision,
+            ..
+        })
+    ));
+    assert_eq!(interpreter.number_format().precision, 0);
+}
+
+#[test]
+fn test_fraction_and_radix_literals() {
+    let mut interpreter = Interpreter::new(Vec::new());
+    interpreter
+        .run_str("@base(m)")
+        .expect("command should succeed");
+
+    let mut run = |input: &str| {
+        interpreter.run_str(input).expect("command should succeed");
+        match interpreter.sink_mut().pop().expect("output should exist") {
+            unitdc::interpreter::Output::Quantity(q) => q.to_string(),
+            _ => panic!("output should be a quantity"),
+        }
+    };
+
+    assert_eq!(run("1|3 1|6 + n"), "0.5 (1)");
+    assert_eq!(run("0x10 0b11 * n"), "48 (1)");
+    assert_eq!(run("-1|4 0x1F + n"), "30.75 (1)");
+    assert_eq!(run("@format(frac) 2|1|4 (m) 0o10 / n"), "9/32 (m)");
+    assert_eq!(run("1|3 3 * n"), "1 (1)");
+
+    let input = "1 1|-3 +";
+    let err = interpreter.run_str(input).expect_err("command should fail");
+    assert!(err.to_string().contains("Invalid number: 1|-3"));
+    assert!(err.render_diagnostic(input).ends_with("\n1 1|-3 +\n  ^^^^"));
+}
+
+#[test]
+fn test_precision_register() {
+    let mut interpreter = Interpreter::new(Vec::new());
+    interpreter
+        .run_str("@base(J) 0 (J) 1e3 @derived(kJ)")
+        .expect("command should succeed");
+
+    let mut run = |input: &str| {
+        interpreter.run_str(input).expect("command should succeed");
+        match interpreter.sink_mut().pop().expect("output should exist") {
+            unitdc::interpreter::Output::Quantity(q) => q.to_string(),
+            _ => panic!("output should be a quantity"),
+        }
+    };
+
+    assert_eq!(run("2 k @format(fix) 1 3 / (kJ) p"), "0.33 (kJ)");
+    assert_eq!(run("4 k @format(sci) 12345 (J) n"), "1.235e4 (J)");
+    assert_eq!(run("@format(eng) 12345 (J) n"), "12.35e3 (J)");
+    assert_eq!(run("@format(frac) n"), "1/3 (kJ)");
+    assert_eq!(run("K n"), "4 (1)");
+
+    assert!(interpreter.run_str("@format(roman)").is_err());
+    assert!(interpreter.run_str("1 2 / k").is_err());
+    assert!(interpreter.run_str("1 (J) k").is_err());
+}
+
+#[test]
+fn test_auto_unit() {
+    let mut interpreter = Interpreter::new(Vec::new());
+    interpreter
+        .run_str(include_str!("../unitdc.rc"))
+        .expect("unitdc.rc should run");
+
+    let mut run = |input: &str| {
+        interpreter.run_str(input).expect("command should succeed");
+        match interpreter.sink_mut().pop().expect("output should exist") {
+            unitdc::interpreter::Output::Quantity(q) => q.to_string(),
+            _ => panic!("output should be a quantity"),
+        }
+    };
+
+    assert_eq!(run("1e-9 (mol) n"), "1e-9 (mol)");
+    assert_eq!(run("@autounit(on) 1e-9 (mol) n"), "1 (nmol)");
+    assert_eq!(run("7200 (s) n"), "7.2 (ks)");
+    assert_eq!(run("7200 (s) (h) n"), "2 (h)");
+    assert_eq!(run("30 (s) n"), "30 (s)");
+    assert_eq!(run("5 (l) n"), "5 (l)");
+    assert_eq!(run("5 (gal) n"), "5 (gal)");
+    assert_eq!(run("0.5 (m) n"), "500 (mm)");
+    assert_eq!(run("0.3 (m) n"), "300 (mm)");
+    assert_eq!(run("2500 (mWh) n"), "2.5 (Wh)");
+    assert_eq!(run("1500 (J) 1 (s) / n"), "1.5 (kW)");
+    assert_eq!(run("5 (mmol) 1 (l) / n"), "5 (mM)");
+    assert_eq!(run("25 (degC) n"), "298.15 (K)");
+    assert_eq!(run("@autounit(off) 7200 (s) n"), "7200 (s)");
+}
+
+#[test]
+fn test_prefixes() {
+    let mut interpreter = Interpreter::new(Vec::new());
+    interpreter
+        .run_str(include_str!("../unitdc.rc"))
+        .expect("unitdc.rc should run");
+
+    let mut run = |input: &str| {
+        interpreter.run_str(input).expect("command should succeed");
+        match interpreter.sink_mut().pop().expect("output should exist") {
+            unitdc::interpreter::Output::Quantity(q) => q.to_string(),
+            _ => panic!("output should be a quantity"),
+        }
+    };
+
+    assert_eq!(run("1 (Gm) (km) n"), "1000000 (km)");
+    assert_eq!(run("3 (fmol) 1 (ul) / (nM) n"), "3 (nM)");
+    assert_eq!(run("1 (kWh) (MJ) n"), "3.6 (MJ)");
+    assert_eq!(run("@base(B) 1 (KiB) (B) n"), "1024 (B)");
+    assert_eq!(run("1 10 / @prefix(my) 5 (mym) (m) n"), "0.5 (m)");
+
+    assert!(interpreter.run_str("1 (mh)").is_err());
+    assert!(interpreter.run_str("1 (mdegC)").is_err());
+    assert!(interpreter.run_str("1e3 @prefix(k)").is_err());
+}
+
+#[test]
+fn test_stack_ops() {
+    let mut interpreter = Interpreter::new(Vec::new());
+
+    let mut run = |input: &str| {
+        interpreter.run_str(input).expect("command should succeed");
+        match interpreter.sink_mut().pop().expect("output should exist") {
+            unitdc::interpreter::Output::QuantityList(qs) => qs
+                .iter()
+                .map(|q| q.number.to_i64().unwrap())
+                .collect::<Vec<_>>(),
+            _ => panic!("output should be a quantity list"),
+        }
+    };
+
+    assert_eq!(run("1 2 3 4 D f"), vec![1, 2, 3]);
+    assert_eq!(run("t f"), vec![2, 3, 1]);
+    assert_eq!(run("2 y f"), vec![2, 3, 1, 2]);
+    assert_eq!(run("3 R f"), vec![3, 1, 2, 2]);
+    assert_eq!(run("z f"), vec![3, 1, 2, 2, 4]);
+    assert_eq!(run("c 1 2 2 Y f"), vec![1, 2, 1, 2]);
+    assert_eq!(run("0 Y 0 R f"), vec![1, 2, 1, 2]);
+
+    match interpreter
+        .run_str("c 1 2 t")
+        .as_ref()
+        .map_err(|e| e.inner())
+    {
+        Err(InterpreterError::StackUnderflow { needed, present }) => {
+            assert_eq!((*needed, *present), (3, 2));
+        }
+        r => panic!("expected stack underflow, got {:?}", r),
+    }
+    assert!(interpreter.run_str("c 1 5 y").is_err());
+    assert!(interpreter.run_str("c 1 1 2 / R").is_err());
+}
+
+#[test]
+fn test_undo_redo() {
+    let mut interpreter = Interpreter::new(Vec::new());
+
+    let stack = |interpreter: &mut Interpreter<Vec<unitdc::interpreter::Output>>, input: &str| {
+        interpreter.run_str(input).expect("command should succeed");
+        interpreter.run_str("f").expect("command should succeed");
+        match interpreter.sink_mut().pop().expect("output should exist") {
+            unitdc::interpreter::Output::QuantityList(qs) => qs
+                .iter()
+                .map(|q| q.number.to_i64().unwrap())
+                .collect::<Vec<_>>(),
+            _ => panic!("output should be a quantity list"),
+        }
+    };
+
+    assert_eq!(stack(&mut interpreter, "1 2"), vec![1, 2]);
+    assert_eq!(stack(&mut interpreter, "3 >x c"), Vec::<i64>::new());
+    assert_eq!(stack(&mut interpreter, "@undo()"), vec![1, 2]);
+    assert!(interpreter.run_str("<x").is_err());
+    assert_eq!(stack(&mut interpreter, "@undo()"), Vec::<i64>::new());
+    assert!(interpreter.run_str("@undo()").is_err());
+    assert_eq!(stack(&mut interpreter, "@redo(2)"), Vec::<i64>::new());
+    assert_eq!(stack(&mut interpreter, "<x"), vec![3]);
+    assert!(interpreter.run_str("@redo()").is_err());
+
+    assert!(interpreter.undo());
+    assert_eq!(stack(&mut interpreter, "4"), vec![4]);
+    assert!(
+        !interpreter.redo(),
+        "a new input should clear the redo history"
+    );
+
+    interpreter.set_history_limit(1);
+    assert!(interpreter.undo());
+    assert!(!interpreter.undo());
+}
+
+#[test]
+fn test_atomic() {
+    let mut interpreter = Interpreter::new(Vec::new());
+    interpreter
+        .run_str("@base(m) @base(s)")
+        .expect("command should succeed");
+
+    interpreter.run_str("5").expect("command should succeed");
+    let err = interpreter
+        .run_str("1 (m) 2 (s) + 3")
+        .expect_err("command should fail");
+    match &err {
+        InterpreterError::AtToken { index, token, .. } => {
+            assert_eq!((*index, token.as_str()), (4, "+"));
+        }
+        e => panic!("expected error at token, got {:?}", e),
+    }
+    assert!(matches!(err.inner(), InterpreterError::QuantityError(_)));
+    interpreter.run_str("z n").expect("command should succeed");
+    match interpreter.sink_mut().pop().expect("output should exist") {
+        unitdc::interpreter::Output::Quantity(q) => assert_eq!(q.number.to_i64(), Some(1)),
+        _ => panic!("output should be a quantity"),
+    }
+
+    interpreter.set_atomic(true);
+    interpreter
+        .run_str("1 (m) 2 (s) >x 3 D D D D")
+        .expect_err("command should fail");
+    interpreter.run_str("f").expect("command should succeed");
+    match interpreter.sink_mut().pop().expect("output should exist") {
+        unitdc::interpreter::Output::QuantityList(qs) => {
+            assert_eq!(qs.len(), 1);
+            assert_eq!(qs[0].number.to_i64(), Some(5));
+        }
+        _ => panic!("output should be a quantity list"),
+    }
+    assert!(interpreter.run_str("<x").is_err());
+}
+
+#[test]
+fn test_error_spans() {
+    let mut interpreter = Interpreter::new(Vec::new());
+    interpreter
+        .run_str("@base(m)")
+        .expect("command should succeed");
+
+    let input = "1 (m)\n2 (furlong) +";
+    let err = interpreter.run_str(input).expect_err("command should fail");
+    let span = err.span().expect("error should have a span");
+    assert_eq!((span.start.line, span.start.column), (2, 3));
+    assert_eq!((span.end.line, span.end.column), (2, 12));
+    assert!(err
+        .render_diagnostic(input)
+        .ends_with("\n2 (furlong) +\n  ^^^^^^^^^"));
+
+    let input = "1 2 + d p $";
+    let err = interpreter.run_str(input).expect_err("command should fail");
+    assert!(matches!(err, InterpreterError::TokenizerError(_, _)));
+    assert!(err
+        .render_diagnostic(input)
+        .ends_with("\n1 2 + d p $\n          ^"));
+
+    let input = "z D D D D D D";
+    let err = interpreter.run_str(input).expect_err("command should fail");
+    assert!(err
+        .render_diagnostic(input)
+        .ends_with("\nz D D D D D D\n          ^"));
+}
+
+#[test]
+fn test_error_serialization() {
+    let mut interpreter = Interpreter::new(Vec::new());
+    interpreter
+        .run_str("@base(m) @base(s)")
+        .expect("command should succeed");
+
+    let err = interpreter
+        .run_str("1 (m)\n2 (furlong) +")
+        .expect_err("command should fail");
+    let json = serde_json::to_value(&err).unwrap();
+    assert_eq!(json["kind"], "undefined_unit");
+    assert_eq!(json["unit"], "furlong");
+    assert_eq!(json["token"], "(furlong)");
+    assert_eq!(json["span"]["start"]["line"], 2);
+    assert_eq!(json["span"]["start"]["column"], 3);
+    assert_eq!(json["message"], err.to_string());
+
+    let err = interpreter
+        .run_str("1 (m) (s)")
+        .expect_err("command should fail");
+    let json = serde_json::to_value(&err).unwrap();
+    assert_eq!(json["kind"], "incompatible_units");
+    assert_eq!(json["units"][0]["unit"]["symbol"], "m");
+
+    let err = interpreter
+        .run_str("1 (m) 1 (s) +")
+        .expect_err("command should fail");
+    let json = serde_json::to_value(&err).unwrap();
+    assert_eq!(json["kind"], "quantity_error");
+    assert_eq!(json["cause"]["reason"], "incompatible_units");
+
+    let err = interpreter
+        .run_str("D D D D")
+        .expect_err("command should fail");
+    let json = serde_json::to_value(&err).unwrap();
+    assert_eq!(json["kind"], "stack_underflow");
+    assert_eq!(json["needed"], 1);
+    assert_eq!(json["present"], 0);
+
+    let err = interpreter.run_str("$").expect_err("command should fail");
+    let json = serde_json::to_value(&err).unwrap();
+    assert_eq!(json["kind"], "tokenizer_error");
+    assert!(json["token"].is_null());
+    assert_eq!(json["span"]["start"]["column"], 1);
+}
+
+#[test]
+fn test_unicode_units() {
+    let mut interpreter = Interpreter::new(Vec::new());
+    interpreter
+        .run_str(include_str!("../unitdc.rc"))
+        .expect("unitdc.rc should run");
+    interpreter
+        .run_str("@base(Ω) 0 (m) 1e-10 @derived(Å) # 0.1 нанометра")
+        .expect("command should succeed");
+
+    interpreter
+        .run_str(
+            "1500 (µm) (mm) p 2 (Å) (nm) p 3 (kΩ) >résistance <résistance (Ω) p 1 (μm/s) (mm/h) p",
+        )
+        .expect("command should succeed");
+    let strs = interpreter
+        .sink()
+        .iter()
+        .filter(|o| !is_definition(o))
+        .map(|o| match o {
+            unitdc::interpreter::Output::Quantity(q) => q.to_string(),
+            _ => panic!("output should be a quantity"),
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(strs, vec!["1.5 (mm)", "0.2 (nm)", "3000 (Ω)", "3.6 (mm/h)"]);
+}
+
+#[test]
+fn test_negation() {
+    let mut interpreter = Interpreter::new(Vec::new());
+    interpreter
+        .run_str("@base(m) 0 (m) 1e3 @derived(km)")
+        .expect("command should succeed");
+
+    interpreter
+        .run_str("3 (km) ~ p 5 -2 + p 5 2- p _1.5e-3 p")
+        .expect("command should succeed");
+    let strs = interpreter
+        .sink_mut()
+        .iter()
+        .filter(|o| !is_definition(o))
+        .map(|o| match o {
+            unitdc::interpreter::Output::Quantity(q) => q.to_string(),
+            _ => panic!("output should be a quantity"),
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(strs, vec!["-3 (km)", "3 (1)", "3 (1)", "-0.0015 (1)"]);
+}
+
+#[test]
+fn test_limits() {
+    let mut interpreter = Interpreter::new(Vec::new());
+    interpreter.set_atomic(true);
+
+    let limit = |r: InterpreterResult<()>| match r.as_ref().map_err(|e| e.inner()) {
+        Err(InterpreterError::LimitExceeded { limit, .. }) => *limit,
+        r => panic!("expected a limit error, got {:?}", r),
+    };
+
+    assert_eq!(
+        limit(interpreter.run_str("1e999999999")),
+        Limit::LiteralExponent
+    );
+    assert_eq!(
+        limit(interpreter.run_str("1 1e-999999999999999999999 +")),
+        Limit::LiteralExponent
+    );
+    interpreter
+        .run_str("1e4096 1e-4096 *")
+        .expect("command should succeed");
+
+    assert_eq!(
+        limit(interpreter.run_str(&format!("3 {} d *", "d * ".repeat(20)))),
+        Limit::NumberBits
+    );
+    assert_eq!(limit(interpreter.run_str("3 1000000 ^")), Limit::NumberBits);
+    interpreter
+        .run_str("1 1000000 ^")
+        .expect("command should succeed");
+
+    assert_eq!(
+        limit(interpreter.run_str("2 1|100000000 ^")),
+        Limit::Exponent
+    );
+    interpreter
+        .run_str("@base(m) 2 1|1000 ^")
+        .expect("command should succeed");
+    assert_eq!(limit(interpreter.run_str("1 (m) 2000 ^")), Limit::Exponent);
+    interpreter
+        .run_str("1 (m) 1000 ^")
+        .expect("command should succeed");
+
+    assert_eq!(
+        limit(interpreter.run_str("100000000 k 1 p")),
+        Limit::Precision
+    );
+    interpreter
+        .run_str("4096 k 1 p 15 k")
+        .expect("command should succeed");
+
+    assert_eq!(
+        limit(interpreter.run_str("1e4000 (m) 1 (m^1000) s")),
+        Limit::NumberBits
+    );
+    assert_eq!(
+        limit(interpreter.run_str("1 26 [z Y] L z p")),
+        Limit::StackDepth
+    );
+    interpreter
+        .run_str(&"1 ".repeat(50_000))
+        .expect("command should succeed");
+    interpreter.run_str("c").expect("command should succeed");
+
+    // Squaring a quantity must not double the number and length of the derived units it is displayed in.
+    interpreter
+        .run_str("0 (m) 1e3 @derived(km) 0 (m) 1e4096 @derived(X)")
+        .expect("command should succeed");
+    interpreter
+        .run_str("1e-3 (km) d * d * d * d * d * d * d * d * d * p c")
+        .expect("command should succeed");
+    interpreter
+        .run_str(&format!("1e-3 (km) {}p c", "d * ".repeat(30)))
+        .expect("command should succeed");
+    assert_eq!(
+        limit(interpreter.run_str("1e-4096 (X) d * d * d *")),
+        Limit::NumberBits
+    );
+
+    interpreter.set_limits(Limits {
+        max_tokens: Some(3),
+        ..Limits::default()
+    });
+    interpreter
+        .run_str("1 2 +")
+        .expect("command should succeed");
+    let err = interpreter
+        .run_str("1 2 + p")
+        .expect_err("command should fail");
+    assert_eq!(limit(Err(err)), Limit::Tokens);
+
+    interpreter.set_limits(Limits {
+        max_stack_depth: Some(3),
+        ..Limits::default()
+    });
+    assert_eq!(limit(interpreter.run_str("1 d d d")), Limit::StackDepth);
+    assert_eq!(limit(interpreter.run_str("1 2 2 Y")), Limit::StackDepth);
+
+    interpreter.set_limits(Limits::unlimited());
+    interpreter
+        .run_str("1e5000")
+        .expect("command should succeed");
+}
+
+#[test]
+fn test_division_by_zero() {
+    let mut interpreter = Interpreter::new(Vec::new());
+    interpreter
+        .run_str("@base(m) @base(s)")
+        .expect("command should succeed");
+
+    let division_by_zero = |r: InterpreterResult<()>| {
+        matches!(
+            r.as_ref().map_err(|e| e.inner()),
+            Err(InterpreterError::QuantityError(
+                QuantityError::DivisionByZero
+            ))
+        )
+    };
+    for input in [
+        "1 0 /",
+        "1 (m) 0 (s) /",
+        "0 (m) 0 @derived(nothing)",
+        "0 @prefix(zero)",
+        "1 (m) 0 (s) 2 (m/s) s",
+        "0 -1 ^",
+    ] {
+        interpreter.run_str("z D D D D D D").ok();
+        assert!(
+            division_by_zero(interpreter.run_str(input)),
+            "{} should fail with division by zero",
+            input
+        );
+    }
+    assert!(interpreter.run_str("0 (nothing)").is_err());
+
+    let mut interpreter = Interpreter::new(Vec::new());
+    for input in ["0 s", "1 2 2 s"] {
+        let result = interpreter.run_str(input);
+        assert!(
+            matches!(
+                result.as_ref().map_err(|e| e.inner()),
+                Err(InterpreterError::NoSolution(_))
+            ),
+            "{} should have no solution, got {:?}",
+            input,
+            result
+        );
+    }
+}
+
+#[test]
+fn test_unit_exponent_overflow() {
+    let mut interpreter = Interpreter::new(Vec::new());
+    interpreter
+        .run_str("@base(m) @base(s) 0 (m) 1e3 @derived(km)")
+        .expect("command should succeed");
+    interpreter.set_atomic(true);
+    interpreter.set_limits(Limits::unlimited());
+
+    for input in [
+        "1 (m) 2000000000 ^ d *",
+        "1 (m) 2147483647 ^ 1 (m) *",
+        "1 (s) 1 (m) -2147483648 ^ /",
+        "1 (m) 4294967296 ^",
+    ] {
+        let result = interpreter.run_str(input);
+        assert!(
+            matches!(
+                result.as_ref().map_err(|e| e.inner()),
+                Err(InterpreterError::QuantityError(
+                    QuantityError::UnitExponentOverflow
+                ))
+            ),
+            "{} should overflow, got {:?}",
+            input,
+            result
+        );
+    }
+    assert!(interpreter.stack().is_empty());
+    interpreter
+        .run_str("1 (m^2000) 1 (m^-2000) * p c")
+        .expect("command should succeed");
+
+    interpreter.set_limits(Limits::default());
+    for input in [
+        "1 (m^2000000000) d *",
+        "1 (km^3000000) p",
+        "1 ((km^1000)^1000) p",
+        "1 (m^2000) p",
+    ] {
+        let result = interpreter.run_str(input);
+        assert!(
+            matches!(
+                result.as_ref().map_err(|e| e.inner()),
+                Err(InterpreterError::UnitExprError(
+                    UnitExprError::ExponentOutOfRange(_)
+                ))
+            ),
+            "{} should be out of range, got {:?}",
+            input,
+            result
+        );
+    }
+    interpreter.set_limits(Limits {
+        max_exponent: Some(3000),
+        ..Limits::default()
+    });
+    interpreter
+        .run_str("1 (m^2000) c")
+        .expect("command should succeed");
+    interpreter.set_limits(Limits::default());
+    let result = interpreter.run_str("1 (m) 2000000000 ^ d *");
+    assert!(matches!(
+        result.as_ref().map_err(|e| e.inner()),
+        Err(InterpreterError::LimitExceeded {
+            limit: Limit::Exponent,
+            ..
+        })
+    ));
+    interpreter
+        .run_str("1 (m) 1000 ^ 1 (m^-1000) * p")
+        .expect("command should succeed");
+}
+
+#[test]
+fn test_macros() {
+    let mut interpreter = Interpreter::new(Vec::new());
+    interpreter
+        .run_str(include_str!("../unitdc.rc"))
+        .expect("unitdc.rc should run");
+
+    interpreter
+        .run_str("[* r /] @def(dilute) 2 (l) 1 (M) 100 (ml) @dilute() (mM) p")
+        .expect("command should succeed");
+    interpreter
+        .run_str("[2 *] >double 3 <double x p 4 x p [nested [brackets]] p D D D")
+        .expect("command should succeed");
+    let strs = interpreter
+        .sink_mut()
+        .drain(..)
+        .filter(|o| !is_definition(o))
+        .map(|o| match o {
+            unitdc::interpreter::Output::Quantity(q) => q.to_string(),
+            unitdc::interpreter::Output::Message(m) => m,
+            _ => panic!("output should be a quantity or a message"),
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(strs, vec!["50 (mM)", "6 (1)", "4 (1)", "nested [brackets]"]);
+
+    let inner = |r: InterpreterResult<()>| r.map_err(|e| e.inner().to_string());
+    let input = "[d x] d x";
+    let err = interpreter.run_str(input).expect_err("command should fail");
+    assert!(matches!(
+        err.inner(),
+        InterpreterError::LimitExceeded {
+            limit: Limit::MacroDepth,
+            ..
+        }
+    ));
+    assert_eq!(
+        err.render_diagnostic(input),
+        "Limit exceeded: macro nesting depth is limited to 64 (at token 2: x, 1:9-1:10)\n[d x] d x\n        ^"
+    );
+    interpreter.run_str("c").expect("command should succeed");
+    assert!(inner(interpreter.run_str("[1] @def(base)")).is_err());
+    assert!(inner(interpreter.run_str("[1] @def(a1)")).is_err());
+    assert!(inner(interpreter.run_str("@nothing()")).is_err());
+    assert!(inner(interpreter.run_str("[1] 2 +")).is_err());
+    assert!(inner(interpreter.run_str("[1] @dilute(x)")).is_err());
+
+    interpreter.set_atomic(true);
+    let err = interpreter
+        .run_str("[1 (m) 1 (s) +] @def(bad) 5 @bad()")
+        .expect_err("command should fail");
+    assert!(matches!(err.inner(), InterpreterError::QuantityError(_)));
+    assert!(
+        inner(interpreter.run_str("@bad()")).is_err(),
+        "failed inputs should not define macros in atomic mode"
+    );
+}
+
+#[test]
+fn test_comparisons() {
+    let mut interpreter = Interpreter::new(Vec::new());
+    interpreter
+        .run_str(include_str!("../unitdc.rc"))
+        .expect("unitdc.rc should run");
+
+    for (input, expected) in [
+        ("5 (ml) 1 (l) <", 1.),
+        ("5 (ml) 1 (l) >", 0.),
+        ("1000 (ml) 1 (l) =", 1.),
+        ("0 (degC) 273 (K) >", 1.),
+        ("-1|3 -0.5 >", 1.),
+    ] {
+        interpreter
+            .run_str(&format!("c {} p", input))
+            .expect("command should succeed");
+        match interpreter.sink_mut().pop().expect("output should exist") {
+            unitdc::interpreter::Output::Quantity(q) => {
+                assert_eq!(q.number.to_f64().unwrap(), expected, "{}", input)
+            }
+            _ => panic!("output should be a quantity"),
+        }
+    }
+    assert!(matches!(
+        interpreter
+            .run_str("1 (m) 1 (s) <")
+            .as_ref()
+            .map_err(|e| e.inner()),
+        Err(InterpreterError::QuantityError(
+            QuantityError::IncompatibleUnits
+        ))
+    ));
+
+    interpreter
+        .run_str("c 2 (M) >conc <conc 1 (M) > [[warning: above 1 M] n] ? <conc 5 (M) > [[unreachable] n] ?")
+        .expect("command should succeed");
+    assert!(matches!(
+        interpreter.sink_mut().as_slice(),
+        [.., unitdc::interpreter::Output::Message(m)] if m == "warning: above 1 M"
+    ));
+    assert!(interpreter.run_str("c 1 2 ?").is_err());
+    assert!(interpreter.run_str("c [p] [p] ?").is_err());
+}
+
+#[test]
+fn test_loops() {
+    let mut interpreter = Interpreter::new(Vec::new());
+    interpreter
+        .run_str(include_str!("../unitdc.rc"))
+        .expect("unitdc.rc should run");
+
+    interpreter
+        .run_str("20 10 5 2 1 5 [(uM) 1 (ml) * 1 (mM) / (ul) n] L 0 [1 n] L")
+        .expect("command should succeed");
+    let strs = interpreter
+        .sink_mut()
+        .drain(..)
+        .filter(|o| !is_definition(o))
+        .map(|o| match o {
+            unitdc::interpreter::Output::Quantity(q) => q.to_string(),
+            _ => panic!("output should be a quantity"),
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        strs,
+        vec!["1 (ul)", "2 (ul)", "5 (ul)", "10 (ul)", "20 (ul)"]
+    );
+
+    interpreter
+        .run_str("1 10 [2 *] L p")
+        .expect("command should succeed");
+    match interpreter.sink_mut().pop().expect("output should exist") {
+        unitdc::interpreter::Output::Quantity(q) => assert_eq!(q.number.to_f64().unwrap(), 1024.),
+        _ => panic!("output should be a quantity"),
+    }
+
+    let inner = |r: InterpreterResult<()>| r.map_err(|e| e.inner().to_string());
+    assert!(inner(interpreter.run_str("c 1.5 [1] L")).is_err());
+    assert!(inner(interpreter.run_str("c -1 [1] L")).is_err());
+    assert!(inner(interpreter.run_str("c 1 (m) [1] L")).is_err());
+    assert!(inner(interpreter.run_str("c [1] 1 L")).is_err());
+    assert!(matches!(
+        interpreter
+            .run_str("c 100000 [] L")
+            .as_ref()
+            .map_err(|e| e.inner()),
+        Err(InterpreterError::LimitExceeded {
+            limit: Limit::LoopIterations,
+            ..
+        })
+    ));
+    interpreter.set_limits(Limits {
+        max_tokens: Some(1000),
+        ..Limits::default()
+    });
+    assert!(matches!(
+        interpreter
+            .run_str("c 100 [100 [1 D] L] L")
+            .as_ref()
+            .map_err(|e| e.inner()),
+        Err(InterpreterError::LimitExceeded {
+            limit: Limit::Tokens,
+            ..
+        })
+    ));
+}
+
+#[test]
+fn test_include() {
+    let mut interpreter = Interpreter::new(Vec::new());
+    interpreter.set_include_resolver(Box::new(
+        [
+            ("unitdc.rc", include_str!("../unitdc.rc")),
+            (
+                "lab/units.rc",
+                "@include(../unitdc.rc) @include(./buffer.rc)",
+            ),
+            (
+                "lab/buffer.rc",
+                "[1 (M) *] @def(molar) 0 (M) 1e-3 @derived(mmolar)",
+            ),
+            ("lab/bad.rc", "1 (m) 1 (s) +"),
+            ("cycle/a.rc", "@include(b.rc)"),
+            ("cycle/b.rc", "@include(/cycle/a.rc)"),
+        ]
+        .into_iter()
+        .map(|(name, contents)| (name.to_string(), contents.to_string()))
+        .collect::<MapResolver>(),
+    ));
+
+    interpreter
+        .run_str("@include(lab/units.rc) 5 @molar() (mmolar) p")
+        .expect("command should succeed");
+    match interpreter.sink_mut().pop().expect("output should exist") {
+        unitdc::interpreter::Output::Quantity(q) => assert_eq!(q.to_string(), "5000 (mmolar)"),
+        _ => panic!("output should be a quantity"),
+    }
+
+    let err = interpreter
+        .run_str("@include(cycle/a.rc)")
+        .expect_err("command should fail");
+    match err.inner() {
+        InterpreterError::IncludeError(IncludeError::Cycle(cycle)) => {
+            assert_eq!(cycle, &["cycle/a.rc", "cycle/b.rc", "cycle/a.rc"])
+        }
+        e => panic!("expected an include cycle, got {:?}", e),
+    }
+
+    let err = interpreter
+        .run_str("@include(lab/bad.rc)")
+        .expect_err("command should fail");
+    assert!(matches!(err.inner(), InterpreterError::QuantityError(_)));
+    assert!(err.to_string().contains("(in lab/bad.rc)"), "{}", err);
+    assert!(matches!(
+        interpreter
+            .run_str("@include(missing.rc)")
+            .as_ref()
+            .map_err(|e| e.inner()),
+        Err(InterpreterError::IncludeError(IncludeError::NotFound(_)))
+    ));
+}
+
+#[test]
+fn test_include_files() {
+    let dir = std::env::temp_dir().join(format!("unitdc-include-{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("sub")).unwrap();
+    std::fs::write(dir.join("main.rc"), "@include(sub/length.rc) 2 (km) (m) p").unwrap();
+    std::fs::write(dir.join("sub/length.rc"), "@base(m) 0 (m) 1e3 @derived(km)").unwrap();
+    std::fs::write(dir.join("sub/self.rc"), "@include(../sub/self.rc)").unwrap();
+
+    let mut interpreter = Interpreter::new(Vec::new());
+    let main = dir.join("main.rc");
+    assert!(matches!(
+        interpreter
+            .run_str(&format!("@include({})", main.display()))
+            .as_ref()
+            .map_err(|e| e.inner()),
+        Err(InterpreterError::IncludeError(IncludeError::Disabled(_)))
+    ));
+
+    interpreter.set_include_resolver(Box::new(FsResolver));
+    interpreter
+        .include(main.to_str().unwrap())
+        .expect("include should succeed");
+    match interpreter.sink_mut().pop().expect("output should exist") {
+        unitdc::interpreter::Output::Quantity(q) => assert_eq!(q.to_string(), "2000 (m)"),
+        _ => panic!("output should be a quantity"),
+    }
+    assert!(matches!(
+        interpreter
+            .include(dir.join("sub/self.rc").to_str().unwrap())
+            .as_ref()
+            .map_err(|e| e.inner()),
+        Err(InterpreterError::IncludeError(IncludeError::Cycle(_)))
+    ));
+    assert!(matches!(
+        interpreter
+            .include(dir.join("missing.rc").to_str().unwrap())
+            .as_ref()
+            .map_err(|e| e.inner()),
+        Err(InterpreterError::IncludeError(IncludeError::Io { .. }))
+    ));
+
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn test_output_sinks() {
+    fn assert_send_static<T: Send + 'static>(_: &T) {}
+
+    let input = "@base(m) 1 (m) p [hello] n 2 f";
+
+    let mut interpreter = Interpreter::new(TextSink::new(Vec::new()));
+    assert_send_static(&interpreter);
+    interpreter.run_str(input).expect("command should succeed");
+    assert!(interpreter.sink_mut().take_error().is_none());
+    assert_eq!(
+        String::from_utf8(interpreter.into_sink().into_inner()).unwrap(),
+        "1 (m)\nhello\n[0]: 2 (1)\n[1]: 1 (m)\n"
+    );
+
+    let mut interpreter = Interpreter::new(JsonLinesSink::new(Vec::new()));
+    assert_send_static(&interpreter);
+    interpreter.run_str(input).expect("command should succeed");
+    let lines = String::from_utf8(interpreter.into_sink().into_inner()).unwrap();
+    let types = lines
+        .lines()
+        .map(|line| {
+            let value: serde_json::Value = serde_json::from_str(line).unwrap();
+            value["type"].as_str().unwrap().to_string()
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        types,
+        vec!["unit_defined", "quantity", "message", "quantity_list"]
+    );
+    assert!(lines.contains(r#"{"type":"message","value":"hello"}"#));
+
+    let mut count = 0;
+    let mut interpreter = Interpreter::new(FnSink(|_| count += 1));
+    interpreter.run_str(input).expect("command should succeed");
+    drop(interpreter);
+    assert_eq!(count, 4);
+}
+
+#[test]
+fn test_output_events() {
+    let mut interpreter = Interpreter::new(Vec::new());
+    interpreter
+        .run_str("@base(m) @base(s) 0 (m) 1e3 @derived(km) 1e4 @prefix(myria) U")
+        .expect("command should succeed");
+    let outputs = interpreter.sink_mut().drain(..).collect::<Vec<_>>();
+    assert!(matches!(
+        outputs.as_slice(),
+        [
+            Output::UnitDefined(Definition::BaseUnit(m)),
+            Output::UnitDefined(Definition::BaseUnit(_)),
+            Output::UnitDefined(Definition::DerivedUnit(km)),
+            Output::UnitDefined(Definition::Prefix { .. }),
+            Output::UnitTable(table),
+        ] if m.symbol == "m" && km.symbol == "km" && table.base_units.len() == 2 && table.derived_units == [km.clone()]
+    ));
+
+    interpreter
+        .run_str("10 (km) >distance [<distance] @def(dist) 2 (s) 5 (m/s) 2 (1) (m) s")
+        .expect("command should succeed");
+    let outputs = interpreter.sink_mut().drain(..).collect::<Vec<_>>();
+    match outputs.as_slice() {
+        [Output::VariableAssigned { name, value }, Output::MacroDefined { name: macro_name }, Output::SolverReport(report)] =>
+        {
+            assert_eq!(name, "distance");
+            assert_eq!(value.to_string(), "10 (km)");
+            assert_eq!(macro_name, "dist");
+            assert_eq!(
+                report
+                    .terms
+                    .iter()
+                    .map(|(q, exponent)| (q.to_string(), *exponent))
+                    .collect::<Vec<_>>(),
+                vec![("2 (s)".to_string(), 1), ("5 (m/s)".to_string(), 1)]
+            );
+            assert_eq!(report.result.to_string(), "10 (m)");
+        }
+        outputs => panic!("unexpected outputs: {:?}", outputs),
+    }
+}
+
+#[test]
+fn test_session() {
+    let mut interpreter = Interpreter::new(Vec::new());
+    interpreter
+        .run_str(include_str!("../unitdc.rc"))
+        .expect("unitdc.rc should run");
+    interpreter
+        .run_str("@base(usd) 0 (usd) 1|100 @derived(cent) 3|7 (cent) >price [2 *] @def(double) 5 (mM) [note] 4 k @format(fix) @autounit(on)")
+        .expect("command should succeed");
+    let session = interpreter.export_session();
+
+    let mut restored = Interpreter::new(Vec::new());
+    restored
+        .import_session(&session)
+        .expect("import should succeed");
+    assert_eq!(restored.snapshot(), interpreter.snapshot());
+    assert_eq!(restored.number_format(), interpreter.number_format());
+    assert!(restored.auto_unit());
+    restored
+        .run_str("D <price (usd) p 2 @double() p")
+        .expect("command should succeed");
+    let strs = restored
+        .sink()
+        .iter()
+        .map(|o| match o {
+            Output::Quantity(q) => q.to_string(),
+            _ => panic!("output should be a quantity"),
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(strs, vec!["4.2857 (musd)", "4.0000 (1)"]);
+
+    restored.undo();
+    restored.undo();
+    assert_eq!(restored.snapshot(), Interpreter::new(Vec::new()).snapshot());
+
+    let before = restored.snapshot();
+    let invalid = |r: InterpreterResult<()>| {
+        matches!(
+            r.as_ref().map_err(|e| e.inner()),
+            Err(InterpreterError::InvalidSession(_))
+        )
+    };
+    assert!(invalid(restored.import_session("not json")));
+    assert!(invalid(restored.import_session(&session.replacen(
+        "\"version\":1",
+        "\"version\":99",
+        1
+    ))));
+    assert!(invalid(restored.import_session(r#"{"version":1}"#)));
+    restored.set_limits(Limits {
+        max_number_bits: Some(8),
+        ..Limits::default()
+    });
+    assert!(restored.import_session(&session).is_err());
+    assert_eq!(restored.snapshot(), before);
+
+    let limit = |r: InterpreterResult<()>| match r.as_ref().map_err(|e| e.inner()) {
+        Err(InterpreterError::LimitExceeded { limit, .. }) => *limit,
+        r => panic!("expected a limit error, got {:?}", r),
+    };
+    restored.set_limits(Limits {
+        max_precision: Some(2),
+        ..Limits::default()
+    });
+    assert_eq!(limit(restored.import_session(&session)), Limit::Precision);
+    restored.set_limits(Limits {
+        max_stack_depth: Some(1),
+        ..Limits::default()
+    });
+    assert_eq!(limit(restored.import_session(&session)), Limit::StackDepth);
+    restored.set_limits(Limits {
+        max_number_bits: Some(1000),
+        ..Limits::default()
+    });
+    for input in [
+        "@base(m) 0 (m) 1e3000 @derived(far)",
+        "1e-3000 @prefix(tiny)",
+    ] {
+        let mut huge = Interpreter::new(Vec::new());
+        huge.run_str(input).expect("command should succeed");
+        assert_eq!(
+            limit(restored.import_session(&huge.export_session())),
+            Limit::NumberBits
+        );
+    }
+    assert_eq!(restored.snapshot(), before);
+
+    // Numbers are imported in lowest terms with a positive denominator, whatever the session says.
+    restored.set_limits(Limits::default());
+    let mut thirds = Interpreter::new(Vec::new());
+    thirds.run_str("1|3").expect("command should succeed");
+    let session = thirds.export_session();
+    let third = r#""number":[[1,[1]],[1,[3]]]"#;
+    assert!(session.contains(third));
+    restored
+        .import_session(&session.replace(third, r#""number":[[1,[2]],[-1,[6]]]"#))
+        .expect("import should succeed");
+    match restored.stack() {
+        [Value::Quantity(q)] => {
+            assert_eq!(q.number, BigRational::new((-1).into(), 3.into()));
+            assert_eq!(q.number.denom(), &3.into());
+        }
+        stack => panic!("unexpected stack: {:?}", stack),
+    }
+    restored.run_str("0 <").expect("command should succeed");
+    match restored.stack() {
+        [Value::Quantity(q)] => assert!(q.number.is_one()),
+        stack => panic!("unexpected stack: {:?}", stack),
     }
+    let before = restored.snapshot();
+    assert!(invalid(restored.import_session(
+        &session.replace(third, r#""number":[[1,[1]],[0,[]]]"#)
+    )));
+    assert_eq!(restored.snapshot(), before);
 }